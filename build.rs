@@ -1,5 +0,0 @@
-use cc;
-
-fn main() {
-    cc::Build::new().file("src/run.c").compile("run");
-}
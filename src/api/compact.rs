@@ -0,0 +1,116 @@
+use chrono::Local;
+use colored::*;
+use serde_json::{self, Value};
+use std::fs;
+use std::io::{ErrorKind, Result};
+use std::path::Path;
+
+use super::{perform, spinner, upload};
+use crate::backend::{Backend, FinishReason};
+use crate::config::flags::Flags;
+use crate::context::{self, Content, Context, Part, Role};
+use crate::usage;
+
+/// Number of recent turns kept as they are when a conversation is compacted.
+pub const DEFAULT_KEEP: usize = 2;
+
+const SUMMARY_PROMPT: &str = "Summarize the conversation so far so the summary can replace it. \
+Keep the facts, decisions, code, file names and open questions later answers may rely on.";
+
+/// Returns whether the history of the session has grown past `threshold`
+/// estimated tokens.
+pub fn over_threshold(savedir: &str, threshold: u64) -> Result<bool> {
+    let context = context::load(savedir)?;
+    let tokens: u64 = context
+        .contents
+        .iter()
+        .map(|content| context::estimate_tokens(&content.parts))
+        .sum();
+    return Ok(tokens > threshold);
+}
+
+/// Asks the model to summarize all but the last `keep` turns of the session
+/// and replaces them with that summary, archiving the whole history under
+/// `history/`. Returns the number of turns that were summarized.
+pub fn compact(flags: &Flags, backend: &dyn Backend, keep: usize) -> Result<usize> {
+    let savedir = flags.savedir.as_str();
+    upload::refresh_uploads(flags, backend)?;
+    let mut context = context::load(savedir)?;
+    let mut turns = context::turns(std::mem::take(&mut context.contents));
+    // summarizing a single turn would not make it any shorter
+    if turns.len() < keep + 2 {
+        return Ok(0);
+    }
+    let count = turns.len() - keep;
+    let mut conversation = Context {
+        systemInstruction: None,
+        safetySettings: context.safetySettings.clone(),
+        contents: turns.drain(..count).flatten().collect(),
+    };
+    conversation.contents.push(summary_request());
+    context::materialize(savedir, &mut conversation)?;
+
+    let spinner = spinner(flags.filter, format!("Summarizing {} turns...", count));
+    let request = backend.request(&conversation, false);
+    let (code, _, body) = perform(&request, None, &flags.network)?;
+    spinner.finish_and_clear();
+    let response = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    let reply = match backend.parse(&response) {
+        Some(reply) if (200..300).contains(&code) => reply,
+        _ => {
+            eprintln!(
+                "{} : the conversation could not be summarized",
+                "Error".red()
+            );
+            return Err(backend.error(code, &response).report());
+        }
+    };
+    usage::record(savedir, backend.model(), &reply)?;
+    // an empty or cut off summary would lose the history it replaces
+    let summary = reply.text();
+    if summary.trim().is_empty() || reply.finish_reason != Some(FinishReason::Stop) {
+        eprintln!(
+            "{} : the summary came back empty or cut off, the conversation was left as it is",
+            "Error".red()
+        );
+        return Err(ErrorKind::Other.into());
+    }
+
+    context.contents = vec![
+        summary_request(),
+        Content {
+            parts: vec![Part::Text { text: summary }],
+            role: Role::Model,
+        },
+    ];
+    context.contents.extend(turns.into_iter().flatten());
+    archive(savedir)?;
+    context::save(savedir, &context)?;
+    return Ok(count);
+}
+
+fn summary_request() -> Content {
+    Content {
+        parts: vec![Part::Text {
+            text: SUMMARY_PROMPT.into(),
+        }],
+        role: Role::User,
+    }
+}
+
+/// Copies the context of the session, as it is before compacting, to
+/// `history/`.
+fn archive(savedir: &str) -> Result<()> {
+    let dir = Path::new(savedir).join("history");
+    fs::create_dir_all(&dir)?;
+    let stamp = Local::now().format("%Y%m%d-%H%M%S");
+    // compacting twice within a second must not overwrite the first archive
+    let mut path = dir.join(format!("context-{}.json", stamp));
+    let mut count = 1;
+    while path.exists() {
+        count += 1;
+        path = dir.join(format!("context-{}-{}.json", stamp, count));
+    }
+    fs::copy(Path::new(savedir).join("context.json"), path)?;
+    return Ok(());
+}
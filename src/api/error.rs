@@ -0,0 +1,97 @@
+use colored::*;
+use std::fmt;
+use std::io;
+
+use super::redact;
+
+/// Exit code for errors that are not reported by the API.
+pub const EXIT_FAILURE: i32 = 1;
+
+/// A failed request, as reported by the API.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The API key is missing, invalid or not allowed to use the API.
+    InvalidKey(String),
+    /// The quota is exhausted or the requests are too frequent.
+    Quota(String),
+    /// The prompt or the answer was blocked, with the reason given.
+    Safety(String),
+    /// The output token limit was reached before any text of the answer.
+    Truncated,
+    /// The request was rejected, e.g. an unknown model or a bad parameter.
+    BadRequest(String),
+    /// An uploaded file the conversation refers to is gone, e.g. expired.
+    MissingFile(String),
+    /// The API failed or is unavailable.
+    Server(u32, String),
+    /// The API could not be reached.
+    Network(String),
+    Other(u32, String),
+}
+
+impl ApiError {
+    /// Process exit code, so scripts can tell the failures apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ApiError::InvalidKey(_) => 3,
+            ApiError::Quota(_) => 4,
+            ApiError::Safety(_) => 5,
+            ApiError::BadRequest(_) | ApiError::MissingFile(_) => 6,
+            ApiError::Server(..) => 7,
+            ApiError::Network(_) => 8,
+            ApiError::Truncated => 9,
+            ApiError::Other(..) => EXIT_FAILURE,
+        }
+    }
+
+    /// Prints the error and turns it into an `io::Error` to be returned.
+    pub fn report(self) -> io::Error {
+        eprintln!("{} : {}", "Error".red(), self);
+        io::Error::other(self)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            ApiError::InvalidKey(message) => format!(
+                "the API key was rejected, check `api` in the config ({})",
+                message
+            ),
+            ApiError::Quota(message) => format!(
+                "the quota is exhausted or the requests are too frequent, try again later ({})",
+                message
+            ),
+            ApiError::Safety(reason) => {
+                format!("the prompt or its answer was blocked ({})", reason)
+            }
+            ApiError::Truncated => "the answer was cut off by the output token limit before any of it was given, \
+                 `terminator continue` (or /continue in a chat) asks again"
+                .to_string(),
+            ApiError::BadRequest(message) => format!("the request was rejected : {}", message),
+            ApiError::MissingFile(message) => format!(
+                "a file attached to the session is no longer available, it may have expired; attach it again or clear the session ({})",
+                message
+            ),
+            ApiError::Server(code, message) => {
+                format!("the API is unavailable (status {}) : {}", code, message)
+            }
+            ApiError::Network(message) => format!("the API could not be reached : {}", message),
+            ApiError::Other(code, message) => {
+                format!("the request failed with status {} : {}", code, message)
+            }
+        };
+        // messages may quote the request, which must not give the key away
+        f.write_str(&redact::text(&message))
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Returns the exit code for an error returned by a command.
+pub fn exit_code(err: &io::Error) -> i32 {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<ApiError>())
+        .map(|err| err.exit_code())
+        .unwrap_or(EXIT_FAILURE)
+}
@@ -1,70 +1,181 @@
+use colored::*;
 use core::time;
-use curl::easy::{Easy, List};
 use indicatif::{ProgressBar, ProgressStyle};
-use serde_json::{self, json, Value};
-use std::fs::File;
+use serde_json::{self, Value};
+use std::borrow::Cow;
+use std::fs::{self, File};
 use std::io::{Read, Result, Write};
+use std::sync::Arc;
 use std::thread;
 
-use crate::{config::flags::Flags, context, utils};
+use crate::backend::{Backend, FinishReason, Request};
+use crate::context::Role;
+use crate::{config::flags::Flags, context, render, usage, utils};
+use error::ApiError;
+use retry::Network;
 
-pub fn api_call(flags: Flags, api: String) -> Result<u32> {
-    let url = format!(
-        r#"https://generativelanguage.googleapis.com/v1beta/models/gemini-1.5-flash-latest:generateContent?key={}"#,
-        api
+pub mod compact;
+pub mod error;
+pub mod redact;
+pub mod retry;
+pub mod stream;
+pub mod upload;
+
+/// Prompt sent to get the rest of an answer cut off by the token limit.
+pub const CONTINUE_PROMPT: &str =
+    "Continue your last answer exactly where it was cut off, without repeating any of it.";
+
+/// Sends the prompt with the conversation of the session, storing the response
+/// in the response file. Fails with an `ApiError` when the API rejects it.
+pub fn api_call(mut flags: Flags, backend: Arc<dyn Backend>) -> Result<()> {
+    // refreshed first, so attaching an expired file again reuses the new upload
+    upload::refresh_uploads(&flags, backend.as_ref())?;
+    upload::upload_attachments(&mut flags, backend.as_ref())?;
+    if let Some(threshold) = flags.compact_threshold {
+        if compact::over_threshold(&flags.savedir, threshold)? {
+            compact::compact(&flags, backend.as_ref(), compact::DEFAULT_KEEP)?;
+        }
+    }
+
+    let mut context = context::load(&flags.savedir)?;
+
+    // a prompt cut off before any answer is sent again as it is by `continue`
+    // and otherwise gives way to the new one
+    let unanswered = matches!(
+        context.contents.last(),
+        Some(content) if matches!(content.role, Role::User)
     );
+    let resend = unanswered && flags.query.as_deref() == Some(CONTINUE_PROMPT);
+    if unanswered && !resend {
+        context.contents.pop();
+        context::pop_user_content(&flags.savedir)?;
+    }
+    if !resend {
+        context.contents.push(context::user_content(&flags));
+    }
+    let dropped = context::apply_window(&mut context, &flags.window);
+    if dropped > 0 {
+        let warning = format!(
+            "{} : {} earlier turns were left out of the request to fit the context window",
+            "Warning".yellow(),
+            dropped
+        );
+        // keep stdout for the answer in filter mode
+        if flags.filter {
+            eprintln!("{}", warning);
+        } else {
+            println!("{}", warning);
+        }
+    }
+    context::materialize(&flags.savedir, &mut context)?;
+
+    let request = backend.request(&context, flags.stream);
+    let spinner = spinner(flags.filter, "Fetching Result...");
+
+    let mut attempt = 0;
+    let (response_code, mut sse) = loop {
+        let mut sse = stream::SseStream::new(backend.clone());
+        let response = utils::overwrite(&flags.responsefile.clone().unwrap())?;
+        let outcome = send(&flags, &request, response, &mut sse, &spinner);
+        // a streamed answer that started printing can not be taken back
+        let delay = match &outcome {
+            _ if spinner.is_finished() || attempt >= flags.network.retries => None,
+            Ok((code, headers)) => retry::retry_delay(attempt, *code, headers),
+            Err(_) => Some(retry::backoff(attempt)),
+        };
+        if let Some(delay) = delay {
+            spinner.set_message(format!(
+                "Retrying in {:.1}s ({}/{})...",
+                delay.as_secs_f64(),
+                attempt + 1,
+                flags.network.retries
+            ));
+            thread::sleep(delay);
+            attempt += 1;
+            continue;
+        }
+        match outcome {
+            Ok((code, _)) => break (code, sse),
+            Err(err) => {
+                spinner.finish_and_clear();
+                return Err(network_error(err, &request).report());
+            }
+        }
+    };
 
-    let mut headers = List::new();
-    headers.append("Content-Type: application/json")?;
-
-    let mut response = utils::overwrite(&flags.responsefile.clone().unwrap())?;
-    let mut easy = Easy::new();
-    easy.url(&url)?;
-    easy.post(true)?;
-    easy.http_headers(headers)?;
-    easy.write_function(move |data| {
-        let _ = response.write_all(data);
-        Ok(data.len())
-    })?;
-    let contextpath = format!("{}/context.json", flags.savedir.clone());
-    let mut contextfile = utils::open(&contextpath)?;
-
-    let mut contextdata = String::new();
-    contextfile.read_to_string(&mut contextdata)?;
-    let mut context: context::Context = serde_json::from_str(&contextdata)?;
-
-    if let Some(image) = &flags.image {
-        context.contents.push(context::Content {
-            parts: vec![
-                context::Part::Text {
-                    text: flags.query.clone().unwrap(),
-                },
-                context::Part::InlineData {
-                    inlineData: context::InlineData {
-                        mimeType: format!(
-                            "image/{}",
-                            flags.image_path.clone().unwrap().split('.').last().unwrap()
-                        ),
-                        data: image.to_string(),
-                    },
-                },
-            ],
-            role: context::Role::User,
-        });
+    if flags.stream {
+        if let Some(chunk) = sse.flush() {
+            if !spinner.is_finished() {
+                spinner.finish_and_clear();
+            }
+            print!("{}", chunk);
+        }
+        let mut response = utils::overwrite(&flags.responsefile.clone().unwrap())?;
+        response.write_all(&sse.finish())?;
+    }
+    if !(200..300).contains(&response_code) {
+        spinner.finish_and_clear();
+        let body = fs::read(flags.responsefile.clone().unwrap())?;
+        let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        return Err(backend.error(response_code, &body).report());
+    }
+    if spinner.is_finished() {
+        println!();
     } else {
-        context.contents.push(context::Content {
-            parts: vec![context::Part::Text {
-                text: flags.query.clone().unwrap(),
-            }],
-            role: context::Role::User,
-        });
+        spinner.finish_with_message("Done!");
     }
 
-    let response_code = std::sync::Arc::new(std::sync::Mutex::new(0));
-    let response_code_clone = response_code.clone();
-    easy.post_fields_copy(json!(context).to_string().as_bytes())?;
+    if !resend {
+        context::add_user_context(&flags)?;
+    }
+    return Ok(());
+}
 
-    let spinner = ProgressBar::new_spinner();
+/// Posts the request once, writing the response to `response` or, when
+/// streaming, printing the answer as it arrives. Returns the status and the
+/// headers of the response.
+fn send(
+    flags: &Flags,
+    request: &Request,
+    mut response: File,
+    sse: &mut stream::SseStream,
+    spinner: &ProgressBar,
+) -> std::result::Result<(u32, Vec<String>), curl::Error> {
+    let mut easy = flags.network.easy(request)?;
+    let mut headers = vec![];
+    {
+        let mut transfer = easy.transfer();
+        transfer.header_function(|header| {
+            headers.push(String::from_utf8_lossy(header).trim_end().to_string());
+            true
+        })?;
+        transfer.write_function(|data| {
+            if !flags.stream {
+                let _ = response.write_all(data);
+                return Ok(data.len());
+            }
+            for chunk in sse.feed(data) {
+                if !spinner.is_finished() {
+                    spinner.finish_and_clear();
+                }
+                print!("{}", chunk);
+                let _ = std::io::stdout().flush();
+            }
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+    let code = easy.response_code()?;
+    Ok((code, headers))
+}
+
+/// Starts the spinner shown while waiting for the API, hidden in filter mode.
+pub fn spinner(hidden: bool, message: impl Into<Cow<'static, str>>) -> ProgressBar {
+    let spinner = if hidden {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new_spinner()
+    };
     spinner.set_style(
         ProgressStyle::default_spinner()
             .tick_chars("⡿⣟⣯⣷⣾⣽⣻⢿")
@@ -72,87 +183,149 @@ pub fn api_call(flags: Flags, api: String) -> Result<u32> {
             .expect("Failed to set template"),
     );
     spinner.enable_steady_tick(time::Duration::from_millis(100));
-    spinner.set_message("Fetching Result...");
-
-    let handle = thread::spawn(move || {
-        easy.perform().unwrap();
-        let code = easy.response_code().unwrap();
-        let mut response_code = response_code_clone.lock().unwrap();
-        *response_code = code;
-    });
-
-    let _ = handle.join();
-    spinner.finish_with_message("Done!");
-    let response_code = *response_code.lock().unwrap();
-
-    if response_code >= 200 && response_code <= 299 {
-        if let Some(_) = &flags.image {
-            context::add_user_context_with_image(&flags)?;
-        } else {
-            context::add_user_context_without_image(&flags)?;
+    spinner.set_message(message);
+    return spinner;
+}
+
+/// Runs a request and returns its status, response headers and body, sending
+/// it again while it fails in a way that may pass.
+///
+/// A `Null` body makes a GET request, unless `upload` gives a file to stream
+/// as the body of a POST; uploads are only sent once.
+pub fn perform(
+    request: &Request,
+    upload: Option<(File, u64)>,
+    network: &Network,
+) -> Result<(u32, Vec<String>, Vec<u8>)> {
+    let retries = if upload.is_some() { 0 } else { network.retries };
+    let mut upload = upload;
+    let mut attempt = 0;
+    loop {
+        let outcome = perform_once(request, upload.take(), network);
+        let delay = match &outcome {
+            _ if attempt >= retries => None,
+            Ok((code, headers, _)) => retry::retry_delay(attempt, *code, headers),
+            Err(_) => Some(retry::backoff(attempt)),
+        };
+        match delay {
+            Some(delay) => {
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            None => return outcome.map_err(|err| network_error(err, request).report()),
         }
     }
+}
+
+fn network_error(err: curl::Error, request: &Request) -> ApiError {
+    ApiError::Network(format!("{} ({})", err, redact::url(&request.url)))
+}
+
+fn perform_once(
+    request: &Request,
+    upload: Option<(File, u64)>,
+    network: &Network,
+) -> std::result::Result<(u32, Vec<String>, Vec<u8>), curl::Error> {
+    let mut easy = network.easy(request)?;
+    let mut file = None;
+    if let Some((upload, size)) = upload {
+        easy.post(true)?;
+        easy.post_field_size(size)?;
+        file = Some(upload);
+    }
 
-    return Ok(response_code);
+    let mut response_headers = vec![];
+    let mut body = vec![];
+    {
+        let mut transfer = easy.transfer();
+        if let Some(file) = file.as_mut() {
+            transfer.read_function(|buf| Ok(file.read(buf).unwrap_or(0)))?;
+        }
+        transfer.header_function(|header| {
+            response_headers.push(String::from_utf8_lossy(header).trim_end().to_string());
+            true
+        })?;
+        transfer.write_function(|data| {
+            body.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+    let code = easy.response_code()?;
+    Ok((code, response_headers, body))
 }
 
-pub fn write_result(flags: &mut Flags) -> Result<()> {
+pub fn write_result(flags: &mut Flags, backend: &dyn Backend) -> Result<()> {
     let mut response_json = File::open(flags.responsefile.clone().unwrap())?;
     let mut content = String::new();
     response_json.read_to_string(&mut content)?;
 
     let data: Value = serde_json::from_str(&content)?;
 
-    let rawtext = data["candidates"][0]["content"]["parts"][0]["text"].to_string();
-    let result_data = &rawtext.trim_matches('"');
-    context::add_model_context(flags, result_data.to_string())?;
-    let result = utils::process_newlines(result_data)
-        .replace("** ", "**")
-        .replace(":", ": ")
-        .replace("\\\"", "\"")
-        .lines()
-        .map(|x| x.to_string())
-        .fold(String::new(), |acc, line| acc + &line + "\n")
-        .to_string();
+    let reply = match backend.parse(&data) {
+        Some(reply) => reply,
+        None => {
+            // the prompt was blocked, so it is not kept either
+            context::pop_user_content(&flags.savedir)?;
+            return Err(backend.error(200, &data).report());
+        }
+    };
+    usage::record(&flags.savedir, backend.model(), &reply)?;
+    let result_data = reply.text();
+    if result_data.is_empty() {
+        // nothing to answer with, so the prompt is not kept either, unless
+        // it is to be sent again by `continue`
+        if reply.finish_reason != Some(FinishReason::MaxTokens) {
+            context::pop_user_content(&flags.savedir)?;
+        }
+        return Err(backend.error(200, &data).report());
+    }
+    flags.notes = reply.notes();
+    context::add_model_context(flags, result_data.clone())?;
+    let result = render::normalize::normalize(&result_data);
 
     let mut md = utils::open(&flags.resultfile.clone().unwrap())?;
 
-    if let Some(image_path) = &flags.image_path {
-        md.write(
-            &format!(
-                r#"
-# Prompt : {}
-## Image :
-![uploaded image]({})
-
-"#,
-                flags.query.clone().unwrap(),
-                image_path
-            )
-            .into_bytes(),
-        )?;
-    } else {
-        md.write(
-            &format!(
-                r#"
-# Prompt : {}
-
-"#,
-                flags.query.clone().unwrap()
-            )
-            .into_bytes(),
-        )?;
-    }
-    md.write_all(&result.clone().into_bytes())?;
-    flags.resulttext = result.into();
-    md.write(
+    md.write_all(
         &format!(
             r#"
+# Prompt : {}
 
-"#
+"#,
+            flags.query.clone().unwrap()
         )
         .into_bytes(),
     )?;
+    if !flags.images.is_empty() {
+        md.write_all(b"## Images :\n")?;
+        for image in &flags.images {
+            md.write_all(format!("![uploaded image]({})\n", image.path).as_bytes())?;
+        }
+        md.write_all(b"\n")?;
+    }
+    if !flags.files.is_empty() {
+        md.write_all(b"## Files :\n")?;
+        for (path, _) in &flags.files {
+            md.write_all(format!("- {}\n", path).as_bytes())?;
+        }
+        md.write_all(b"\n")?;
+    }
+    if !flags.attachments.is_empty() {
+        md.write_all(b"## Attachments :\n")?;
+        for attachment in &flags.attachments {
+            md.write_all(format!("- {}\n", attachment.path).as_bytes())?;
+        }
+        md.write_all(b"\n")?;
+    }
+    if let Some(stdin) = &flags.stdin {
+        md.write_all(format!("## Input :\n```\n{}\n```\n\n", stdin.trim_end()).as_bytes())?;
+    }
+    md.write_all(&result.clone().into_bytes())?;
+    for note in &flags.notes {
+        md.write_all(format!("\n> **Warning** : {}\n", note).as_bytes())?;
+    }
+    flags.resulttext = result.into();
+    md.write_all(b"\n\n")?;
 
     return Ok(());
 }
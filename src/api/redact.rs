@@ -0,0 +1,68 @@
+use std::sync::Mutex;
+
+/// Query parameters that grant access, masked in the urls that are printed.
+const SENSITIVE_PARAMS: &[&str] = &["key", "upload_id"];
+
+/// Secrets masked wherever they show up in printed text.
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Masks every later occurrence of `secret` in the text passed to `text`.
+pub fn register(secret: &str) {
+    let secret = secret.trim();
+    if secret.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.lock().unwrap();
+    if !secrets.iter().any(|known| known == secret) {
+        secrets.push(secret.into());
+    }
+}
+
+/// Hides a secret, keeping its last characters when it is long enough for
+/// them to not give it away, so keys can still be told apart.
+pub fn mask(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() < 12 {
+        return "********".into();
+    }
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    return format!("********{}", tail);
+}
+
+/// Masks the values of the query parameters of a url that grant access.
+pub fn url(url: &str) -> String {
+    let (base, query) = match url.split_once('?') {
+        Some(parts) => parts,
+        None => return url.into(),
+    };
+    let query: Vec<String> = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, value)) if SENSITIVE_PARAMS.contains(&name) => {
+                format!("{}={}", name, mask(value))
+            }
+            _ => param.into(),
+        })
+        .collect();
+    return format!("{}?{}", base, query.join("&"));
+}
+
+/// Masks the registered secrets and the sensitive parts of the urls of a
+/// text before it is printed.
+pub fn text(text: &str) -> String {
+    let mut text = text.to_string();
+    for secret in SECRETS.lock().unwrap().iter() {
+        text = text.replace(secret.as_str(), &mask(secret));
+    }
+    return text
+        .split(' ')
+        .map(|word| {
+            if word.contains("://") {
+                url(word)
+            } else {
+                word.into()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+}
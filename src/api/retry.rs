@@ -0,0 +1,106 @@
+use chrono::{DateTime, Utc};
+use curl::easy::{Easy, List};
+use rand::Rng;
+use std::time::Duration;
+
+use crate::backend::Request;
+use crate::config::configfile::Config;
+
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const BACKOFF_BASE_MS: u64 = 1000;
+const BACKOFF_MAX_MS: u64 = 32_000;
+
+/// Retries and timeouts of the requests sent to the API.
+#[derive(Clone, Copy, Debug)]
+pub struct Network {
+    /// Number of times a failed request is sent again.
+    pub retries: u32,
+    /// Seconds allowed to connect to the API.
+    pub connect_timeout: u64,
+    /// Seconds allowed for a whole request, unlimited when not set.
+    pub timeout: Option<u64>,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_RETRIES,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: None,
+        }
+    }
+}
+
+impl Network {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            retries: config.retries.unwrap_or(DEFAULT_RETRIES),
+            connect_timeout: config.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            timeout: config.timeout,
+        }
+    }
+
+    /// Prepares a handle for the request, with the timeouts applied.
+    ///
+    /// A `Null` body makes a GET request.
+    pub fn easy(&self, request: &Request) -> Result<Easy, curl::Error> {
+        let mut headers = List::new();
+        for header in &request.headers {
+            headers.append(header)?;
+        }
+        let mut easy = Easy::new();
+        easy.url(&request.url)?;
+        easy.http_headers(headers)?;
+        easy.connect_timeout(Duration::from_secs(self.connect_timeout))?;
+        if let Some(timeout) = self.timeout {
+            easy.timeout(Duration::from_secs(timeout))?;
+        }
+        if !request.body.is_null() {
+            easy.post(true)?;
+            easy.post_fields_copy(request.body.to_string().as_bytes())?;
+        }
+        Ok(easy)
+    }
+}
+
+/// Returns how long to wait before sending a request again after it ended
+/// with `status`, or `None` when sending it again would not help.
+///
+/// A server asking to wait longer than the longest backoff is not waited for,
+/// so the failure is reported instead of the command hanging.
+pub fn retry_delay(attempt: u32, status: u32, headers: &[String]) -> Option<Duration> {
+    if !matches!(status, 408 | 429 | 500 | 502 | 503 | 504) {
+        return None;
+    }
+    match retry_after(headers) {
+        Some(delay) if delay > Duration::from_millis(BACKOFF_MAX_MS) => None,
+        Some(delay) => Some(delay),
+        None => Some(backoff(attempt)),
+    }
+}
+
+/// Exponential backoff with jitter, so clients failing together do not retry
+/// together.
+pub fn backoff(attempt: u32) -> Duration {
+    let delay = BACKOFF_BASE_MS
+        .saturating_mul(1 << attempt.min(16))
+        .min(BACKOFF_MAX_MS);
+    let jitter = rand::thread_rng().gen_range(0..=delay / 2);
+    Duration::from_millis(delay / 2 + jitter)
+}
+
+/// Reads the `Retry-After` header, given in seconds or as a date.
+fn retry_after(headers: &[String]) -> Option<Duration> {
+    let value = headers.iter().find_map(|header| {
+        let (key, value) = header.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case("retry-after")
+            .then(|| value.trim().to_string())
+    })?;
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(&value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
@@ -0,0 +1,102 @@
+use crate::backend::Backend;
+use serde_json::{self, Value};
+use std::sync::Arc;
+
+/// Incremental reader for the server-sent events returned by
+/// `streamGenerateContent?alt=sse`.
+///
+/// Bytes are fed in as curl hands them over; every complete event yields the
+/// text it carries so it can be printed straight away, while the full answer is
+/// accumulated to be stored as a regular, non-streamed response at the end.
+pub struct SseStream {
+    backend: Arc<dyn Backend>,
+    pending: Vec<u8>,
+    data: String,
+    raw: Vec<u8>,
+    text: String,
+    last: Option<Value>,
+}
+
+impl SseStream {
+    pub fn new(backend: Arc<dyn Backend>) -> Self {
+        Self {
+            backend,
+            pending: Vec::new(),
+            data: String::new(),
+            raw: Vec::new(),
+            text: String::new(),
+            last: None,
+        }
+    }
+
+    /// Consumes a chunk of the response body and returns the text of every
+    /// event that was completed by it.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.raw.extend_from_slice(bytes);
+        self.pending.extend_from_slice(bytes);
+
+        let mut chunks = vec![];
+        while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                if let Some(chunk) = self.dispatch() {
+                    chunks.push(chunk);
+                }
+            } else if let Some(data) = line.strip_prefix("data:") {
+                if !self.data.is_empty() {
+                    self.data.push('\n');
+                }
+                self.data.push_str(data.trim_start());
+            }
+        }
+        chunks
+    }
+
+    /// Dispatches an event left unterminated when the body ended.
+    pub fn flush(&mut self) -> Option<String> {
+        if !self.pending.is_empty() {
+            self.pending.push(b'\n');
+            let mut chunks = self.feed(b"");
+            chunks.extend(self.dispatch());
+            return Some(chunks.concat()).filter(|chunk| !chunk.is_empty());
+        }
+        self.dispatch()
+    }
+
+    fn dispatch(&mut self) -> Option<String> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let data = std::mem::take(&mut self.data);
+        let event: Value = serde_json::from_str(&data).ok()?;
+
+        let chunk = self.backend.stream_text(&event);
+        self.text.push_str(&chunk);
+        self.last = Some(event);
+
+        if chunk.is_empty() {
+            return None;
+        }
+        Some(chunk)
+    }
+
+    /// Returns the body to be stored in the response file.
+    ///
+    /// When at least one event was received this is the final event with its
+    /// parts replaced by the whole answer, so it has the same shape as a
+    /// non-streaming response (finish reason and usage included). Otherwise
+    /// the server did not stream (usually an error) and the raw body is kept.
+    pub fn finish(&mut self) -> Vec<u8> {
+        match self.last.take() {
+            Some(last) => self
+                .backend
+                .stream_response(last, &self.text)
+                .to_string()
+                .into_bytes(),
+            None => std::mem::take(&mut self.raw),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use chrono::{DateTime, Duration, Utc};
+use colored::*;
+use core::time;
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use serde_json::{self, json, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Result, Write};
+use std::path::Path;
+use std::thread;
+
+use super::retry::Network;
+use super::{perform, spinner};
+use crate::backend::{Backend, FileState, RemoteFile};
+use crate::context::{self, FileData, Part};
+use crate::{config::flags::Flags, utils};
+
+/// Media types accepted as attachments, by file extension.
+const MEDIA_TYPES: &[(&str, &str)] = &[
+    ("pdf", "application/pdf"),
+    ("wav", "audio/wav"),
+    ("mp3", "audio/mp3"),
+    ("aiff", "audio/aiff"),
+    ("aac", "audio/aac"),
+    ("ogg", "audio/ogg"),
+    ("flac", "audio/flac"),
+    ("mp4", "video/mp4"),
+    ("mpeg", "video/mpeg"),
+    ("mpg", "video/mpeg"),
+    ("mov", "video/mov"),
+    ("avi", "video/avi"),
+    ("flv", "video/x-flv"),
+    ("webm", "video/webm"),
+    ("wmv", "video/wmv"),
+    ("3gp", "video/3gpp"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("webp", "image/webp"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+];
+
+/// An uploaded file is not reused when it expires sooner than this.
+const EXPIRY_MARGIN_MINUTES: i64 = 60;
+
+/// A file uploaded for the session, kept in `uploads.json` so attaching the
+/// same file again reuses it until it expires.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Upload {
+    pub path: String,
+    pub hash: String,
+    pub name: String,
+    pub uri: String,
+    pub mime_type: String,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// Returns the media type of an attachment from its extension.
+pub fn media_type(path: &str) -> Option<&'static str> {
+    let ext = Path::new(path)
+        .extension()?
+        .to_string_lossy()
+        .to_lowercase();
+    MEDIA_TYPES
+        .iter()
+        .find(|(known, _)| *known == ext)
+        .map(|(_, mime_type)| *mime_type)
+}
+
+pub fn load(savedir: &str) -> Result<Vec<Upload>> {
+    let path = format!("{}/uploads.json", savedir);
+    if !Path::new(&path).exists() {
+        return Ok(vec![]);
+    }
+    let text = fs::read_to_string(&path)?;
+    let uploads: Vec<Upload> = serde_json::from_str(&text)?;
+    return Ok(uploads);
+}
+
+pub fn save(savedir: &str, uploads: &[Upload]) -> Result<()> {
+    let mut file = utils::overwrite(&format!("{}/uploads.json", savedir))?;
+    file.write_all(&json!(uploads).to_string().into_bytes())?;
+    return Ok(());
+}
+
+/// Uploads the attachments of the next user turn, reusing the files of the
+/// session that were already uploaded and have not expired.
+pub fn upload_attachments(flags: &mut Flags, backend: &dyn Backend) -> Result<()> {
+    if flags
+        .attachments
+        .iter()
+        .all(|attachment| attachment.uri.is_some())
+    {
+        return Ok(());
+    }
+    let mut uploads = load(&flags.savedir)?;
+
+    for attachment in flags.attachments.iter_mut() {
+        if attachment.uri.is_some() {
+            continue;
+        }
+        let hash = sha256::try_digest(Path::new(&attachment.path))?;
+        if let Some(upload) = uploads
+            .iter()
+            .find(|upload| upload.hash == hash && !expiring(upload))
+        {
+            attachment.uri = upload.uri.clone().into();
+            continue;
+        }
+        let file = upload(
+            &attachment.path,
+            &attachment.mime_type,
+            backend,
+            &flags.network,
+            flags.filter,
+        )?;
+        attachment.uri = file.uri.clone().into();
+        // expired uploads are kept until then, so the history can be refreshed
+        uploads.retain(|upload| upload.hash != hash);
+        uploads.push(Upload {
+            // refreshing may happen from another directory
+            path: utils::get_absolute_path(&attachment.path)?,
+            hash,
+            name: file.name,
+            uri: file.uri,
+            mime_type: file.mime_type,
+            expires: file.expires,
+        });
+    }
+    return save(&flags.savedir, &uploads);
+}
+
+/// Uploads again the files of the conversation that expired or are about
+/// to, since the provider forgets them after a while. A file that is gone or
+/// changed since is replaced by a note saying so.
+pub fn refresh_uploads(flags: &Flags, backend: &dyn Backend) -> Result<()> {
+    let mut uploads = load(&flags.savedir)?;
+    if !uploads.iter().any(expiring) {
+        return Ok(());
+    }
+    let mut context = context::load(&flags.savedir)?;
+    // the new uri of each expired upload, or the path of its file when it is gone
+    let mut refreshed: HashMap<String, std::result::Result<String, String>> = HashMap::new();
+    for content in context.contents.iter_mut() {
+        for part in content.parts.iter_mut() {
+            let (uri, mime_type) = match part {
+                Part::FileData { fileData } => {
+                    (fileData.fileUri.clone(), fileData.mimeType.clone())
+                }
+                _ => continue,
+            };
+            if !refreshed.contains_key(&uri) {
+                let record = match uploads.iter_mut().find(|upload| upload.uri == uri) {
+                    Some(record) if expiring(record) => record,
+                    _ => continue,
+                };
+                let unchanged = sha256::try_digest(Path::new(&record.path))
+                    .is_ok_and(|hash| hash == record.hash);
+                if unchanged {
+                    let file = upload(
+                        &record.path,
+                        &record.mime_type,
+                        backend,
+                        &flags.network,
+                        flags.filter,
+                    )?;
+                    record.name = file.name;
+                    record.uri = file.uri;
+                    record.expires = file.expires;
+                    refreshed.insert(uri.clone(), Ok(record.uri.clone()));
+                } else {
+                    let warning = format!(
+                        "{} : {} expired and was changed or removed since, it is left out of the conversation",
+                        "Warning".yellow(),
+                        record.path.yellow()
+                    );
+                    if flags.filter {
+                        eprintln!("{}", warning);
+                    } else {
+                        println!("{}", warning);
+                    }
+                    refreshed.insert(uri.clone(), Err(record.path.clone()));
+                }
+            }
+            *part = match &refreshed[&uri] {
+                Ok(uri) => Part::FileData {
+                    fileData: FileData {
+                        mimeType: mime_type,
+                        fileUri: uri.clone(),
+                    },
+                },
+                Err(path) => Part::Text {
+                    text: format!("[the attached file {} is no longer available]", path),
+                },
+            };
+        }
+    }
+    if !refreshed.is_empty() {
+        context::save(&flags.savedir, &context)?;
+        save(&flags.savedir, &uploads)?;
+    }
+    return Ok(());
+}
+
+/// Whether an upload expires too soon to be sent with a prompt.
+fn expiring(upload: &Upload) -> bool {
+    let fresh = Utc::now() + Duration::minutes(EXPIRY_MARGIN_MINUTES);
+    upload.expires.is_some_and(|expires| expires <= fresh)
+}
+
+/// Sends a file through the resumable upload protocol and waits until the
+/// provider has processed it.
+fn upload(
+    path: &str,
+    mime_type: &str,
+    backend: &dyn Backend,
+    network: &Network,
+    quiet: bool,
+) -> Result<RemoteFile> {
+    let size = fs::metadata(path)?.len();
+    let name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or(path.into());
+
+    let spinner = spinner(quiet, format!("Uploading {}...", name));
+
+    let start = backend.upload_request(&name, mime_type, size);
+    let (code, headers, body) = perform(&start, None, network)?;
+    let send = match backend.finish_upload_request(&headers) {
+        Some(send) if (200..300).contains(&code) => send,
+        _ => return Err(failed(&spinner, backend, path, code, &body)),
+    };
+    let (code, _, body) = perform(&send, Some((File::open(path)?, size)), network)?;
+    let mut file = match serde_json::from_slice(&body)
+        .ok()
+        .and_then(|response: Value| backend.uploaded_file(&response))
+    {
+        Some(file) if (200..300).contains(&code) => file,
+        _ => return Err(failed(&spinner, backend, path, code, &body)),
+    };
+
+    // videos are processed before they can be used in a prompt
+    while file.state == FileState::Processing {
+        spinner.set_message(format!("Processing {}...", name));
+        thread::sleep(time::Duration::from_secs(2));
+        let state = backend.file_request(&file.name);
+        let (code, _, body) = perform(&state, None, network)?;
+        file = match serde_json::from_slice(&body)
+            .ok()
+            .and_then(|response: Value| backend.uploaded_file(&response))
+        {
+            Some(file) if (200..300).contains(&code) => file,
+            _ => return Err(failed(&spinner, backend, path, code, &body)),
+        };
+    }
+    if file.state == FileState::Failed {
+        spinner.finish_and_clear();
+        eprintln!(
+            "{} : {} could not be processed",
+            "Error".red(),
+            path.yellow()
+        );
+        return Err(ErrorKind::InvalidData.into());
+    }
+
+    spinner.finish_with_message(format!("Uploaded {}", name));
+    return Ok(file);
+}
+
+fn failed(
+    spinner: &ProgressBar,
+    backend: &dyn Backend,
+    path: &str,
+    code: u32,
+    body: &[u8],
+) -> std::io::Error {
+    spinner.finish_and_clear();
+    eprintln!("{} : could not upload {}", "Error".red(), path.yellow());
+    let response = serde_json::from_slice(body).unwrap_or(Value::Null);
+    return backend.error(code, &response).report();
+}
@@ -0,0 +1,260 @@
+use super::{Backend, FileState, FinishReason, Generation, RemoteFile, Reply, Request, Usage};
+use crate::api::error::ApiError;
+use crate::api::redact;
+use crate::config::configfile::Config;
+use crate::config::flags::Flags;
+use crate::context::{Content, Context, Role};
+use chrono::{DateTime, Utc};
+use serde_json::{self, json, Value};
+
+const BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+const FILES_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+const UPLOAD_URL: &str = "https://generativelanguage.googleapis.com/upload/v1beta/files";
+const DEFAULT_MODEL: &str = "gemini-1.5-flash-latest";
+
+pub struct Gemini {
+    api: String,
+    model: String,
+    generation: Generation,
+}
+
+impl Gemini {
+    pub fn new(config: &Config, flags: &Flags) -> Self {
+        redact::register(&config.api);
+        Self {
+            api: config.api.clone(),
+            model: flags.model.clone().unwrap_or(DEFAULT_MODEL.into()),
+            generation: flags.generation.clone(),
+        }
+    }
+
+    /// The key goes in a header rather than the url, which proxies and
+    /// curl's verbose output may log.
+    fn key_header(&self) -> String {
+        format!("x-goog-api-key: {}", self.api)
+    }
+
+    fn generation_config(&self) -> Value {
+        let generation = &self.generation;
+        let mut config = json!({});
+        if let Some(temperature) = generation.temperature {
+            config["temperature"] = temperature.into();
+        }
+        if let Some(top_p) = generation.top_p {
+            config["topP"] = top_p.into();
+        }
+        if let Some(top_k) = generation.top_k {
+            config["topK"] = top_k.into();
+        }
+        if let Some(max_output_tokens) = generation.max_output_tokens {
+            config["maxOutputTokens"] = max_output_tokens.into();
+        }
+        if let Some(stop_sequences) = &generation.stop_sequences {
+            config["stopSequences"] = stop_sequences.clone().into();
+        }
+        if let Some(candidate_count) = generation.candidate_count {
+            config["candidateCount"] = candidate_count.into();
+        }
+        config
+    }
+}
+
+impl Backend for Gemini {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn request(&self, context: &Context, stream: bool) -> Request {
+        let method = if stream {
+            "streamGenerateContent?alt=sse"
+        } else {
+            "generateContent"
+        };
+        let mut body = json!(context);
+        let generation_config = self.generation_config();
+        if generation_config != json!({}) {
+            body["generationConfig"] = generation_config;
+        }
+        Request {
+            url: format!("{}/{}:{}", BASE_URL, self.model, method),
+            headers: vec![self.key_header(), "Content-Type: application/json".into()],
+            body,
+        }
+    }
+
+    fn stream_text(&self, event: &Value) -> String {
+        event["candidates"][0]["content"]["parts"]
+            .as_array()
+            .map(|parts| {
+                parts
+                    .iter()
+                    .filter_map(|part| part["text"].as_str())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn stream_response(&self, mut last: Value, text: &str) -> Value {
+        // a blocked prompt has no candidate to put the answer in
+        if !last["candidates"][0].is_object() {
+            return last;
+        }
+        last["candidates"][0]["content"]["parts"] = json!([{ "text": text }]);
+        last["candidates"][0]["content"]["role"] = "model".into();
+        last
+    }
+
+    fn parse(&self, response: &Value) -> Option<Reply> {
+        let candidate = &response["candidates"][0];
+        if !candidate.is_object() {
+            return None;
+        }
+        // a stopped answer may come without any content
+        let content = serde_json::from_value(candidate["content"].clone()).unwrap_or(Content {
+            parts: vec![],
+            role: Role::Model,
+        });
+        let usage = &response["usageMetadata"];
+        let usage = usage.is_object().then(|| Usage {
+            prompt_tokens: usage["promptTokenCount"].as_u64().unwrap_or(0),
+            candidate_tokens: usage["candidatesTokenCount"].as_u64().unwrap_or(0),
+            total_tokens: usage["totalTokenCount"].as_u64().unwrap_or(0),
+        });
+        let finish_reason = candidate["finishReason"]
+            .as_str()
+            .map(|reason| match reason {
+                "STOP" => FinishReason::Stop,
+                "MAX_TOKENS" => FinishReason::MaxTokens,
+                "SAFETY" => FinishReason::Safety,
+                "RECITATION" => FinishReason::Recitation,
+                other => FinishReason::Other(other.into()),
+            });
+        let safety = candidate["safetyRatings"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|rating| {
+                rating["blocked"] == true
+                    || matches!(rating["probability"].as_str(), Some("MEDIUM" | "HIGH"))
+            })
+            .map(|rating| {
+                format!(
+                    "{} {}",
+                    rating["category"]
+                        .as_str()
+                        .unwrap_or("")
+                        .trim_start_matches("HARM_CATEGORY_"),
+                    rating["probability"].as_str().unwrap_or("")
+                )
+            })
+            .collect();
+        Some(Reply {
+            content,
+            usage,
+            finish_reason,
+            safety,
+        })
+    }
+
+    fn error(&self, status: u32, response: &Value) -> ApiError {
+        if let Some(reason) = response["promptFeedback"]["blockReason"].as_str() {
+            return ApiError::Safety(reason.into());
+        }
+        if let Some(reason) = response["candidates"][0]["finishReason"].as_str() {
+            if status == 200 {
+                return match reason {
+                    "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" => {
+                        ApiError::Safety(reason.into())
+                    }
+                    "MAX_TOKENS" => ApiError::Truncated,
+                    _ => ApiError::Other(status, format!("the answer was empty ({})", reason)),
+                };
+            }
+        }
+        let error = &response["error"];
+        let message = error["message"]
+            .as_str()
+            .unwrap_or("no details were given")
+            .to_string();
+        let invalid_key = error["details"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|detail| detail["reason"] == "API_KEY_INVALID");
+        // an expired or deleted upload is denied like a bad key
+        let missing_file = message.contains("File ") || message.contains("files/");
+        match (status, error["status"].as_str().unwrap_or("")) {
+            (_, "PERMISSION_DENIED") | (403, _) if missing_file => ApiError::MissingFile(message),
+            (_, "UNAUTHENTICATED" | "PERMISSION_DENIED") | (401 | 403, _) => {
+                ApiError::InvalidKey(message)
+            }
+            _ if invalid_key => ApiError::InvalidKey(message),
+            (_, "RESOURCE_EXHAUSTED") | (429, _) => ApiError::Quota(message),
+            (400 | 404, _) => ApiError::BadRequest(message),
+            (500..=599, _) => ApiError::Server(status, message),
+            _ => ApiError::Other(status, message),
+        }
+    }
+
+    fn upload_request(&self, name: &str, mime_type: &str, size: u64) -> Request {
+        Request {
+            url: UPLOAD_URL.into(),
+            headers: vec![
+                self.key_header(),
+                "X-Goog-Upload-Protocol: resumable".into(),
+                "X-Goog-Upload-Command: start".into(),
+                format!("X-Goog-Upload-Header-Content-Length: {}", size),
+                format!("X-Goog-Upload-Header-Content-Type: {}", mime_type),
+                "Content-Type: application/json".into(),
+            ],
+            body: json!({ "file": { "display_name": name } }),
+        }
+    }
+
+    fn finish_upload_request(&self, start_headers: &[String]) -> Option<Request> {
+        let url = start_headers.iter().find_map(|header| {
+            let (key, value) = header.split_once(':')?;
+            key.eq_ignore_ascii_case("x-goog-upload-url")
+                .then(|| value.trim().to_string())
+        })?;
+        Some(Request {
+            url,
+            headers: vec![
+                "X-Goog-Upload-Offset: 0".into(),
+                "X-Goog-Upload-Command: upload, finalize".into(),
+            ],
+            body: Value::Null,
+        })
+    }
+
+    fn file_request(&self, name: &str) -> Request {
+        Request {
+            url: format!("{}/{}", FILES_URL, name),
+            headers: vec![self.key_header()],
+            body: Value::Null,
+        }
+    }
+
+    fn uploaded_file(&self, response: &Value) -> Option<RemoteFile> {
+        // the upload answers with `{ "file": ... }`, files.get with the file itself
+        let file = if response["file"].is_object() {
+            &response["file"]
+        } else {
+            response
+        };
+        let state = match file["state"].as_str() {
+            Some("PROCESSING") => FileState::Processing,
+            Some("FAILED") => FileState::Failed,
+            _ => FileState::Active,
+        };
+        Some(RemoteFile {
+            name: file["name"].as_str()?.into(),
+            uri: file["uri"].as_str()?.into(),
+            mime_type: file["mimeType"].as_str()?.into(),
+            expires: file["expirationTime"]
+                .as_str()
+                .and_then(|time| time.parse::<DateTime<Utc>>().ok()),
+            state,
+        })
+    }
+}
@@ -0,0 +1,206 @@
+use crate::api::error::ApiError;
+use crate::config::configfile::Config;
+use crate::config::flags::Flags;
+use crate::context::{Content, Context, Part};
+use chrono::{DateTime, Utc};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{ErrorKind, Result};
+use std::sync::Arc;
+
+pub mod gemini;
+
+/// An HTTP request ready to be posted by `api::api_call`.
+pub struct Request {
+    pub url: String,
+    pub headers: Vec<String>,
+    pub body: Value,
+}
+
+#[derive(Clone, Copy, Default, Debug, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub candidate_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.candidate_tokens += other.candidate_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// Sampling parameters applied to every generated answer.
+#[derive(Clone, Default, Debug)]
+pub struct Generation {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u32>,
+    pub max_output_tokens: Option<u32>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub candidate_count: Option<u32>,
+}
+
+/// Processing state of a file uploaded to the provider.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileState {
+    Processing,
+    Active,
+    Failed,
+}
+
+/// A file stored by the provider, referenced by its uri in the conversation.
+#[derive(Clone, Debug)]
+pub struct RemoteFile {
+    pub name: String,
+    pub uri: String,
+    pub mime_type: String,
+    pub expires: Option<DateTime<Utc>>,
+    pub state: FileState,
+}
+
+/// Why the model stopped generating the answer, recorded in `usage.json` as
+/// the API names it, e.g. `MAX_TOKENS`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum FinishReason {
+    Stop,
+    /// The answer was cut off by the output token limit.
+    MaxTokens,
+    /// The answer was stopped by the safety filters.
+    Safety,
+    /// The answer was stopped for reciting protected content.
+    Recitation,
+    #[serde(untagged)]
+    Other(String),
+}
+
+impl FinishReason {
+    /// Explains a reason other than the natural end of the answer.
+    pub fn note(&self) -> Option<String> {
+        match self {
+            FinishReason::Stop => None,
+            FinishReason::MaxTokens => Some(
+                "the answer was cut off by the output token limit, \
+                 `terminator continue` (or /continue in a chat) gets the rest"
+                    .into(),
+            ),
+            FinishReason::Safety => Some("the answer was stopped by the safety filters".into()),
+            FinishReason::Recitation => {
+                Some("the answer was stopped because it recited protected content".into())
+            }
+            FinishReason::Other(reason) => Some(format!("the answer was stopped ({})", reason)),
+        }
+    }
+}
+
+/// The model turn produced for a conversation along with what it cost.
+pub struct Reply {
+    pub content: Content,
+    pub usage: Option<Usage>,
+    pub finish_reason: Option<FinishReason>,
+    /// Safety categories the answer was rated likely to fall in.
+    pub safety: Vec<String>,
+}
+
+impl Reply {
+    /// Warnings about how the answer ended, to show along with it.
+    pub fn notes(&self) -> Vec<String> {
+        let mut notes: Vec<String> = self
+            .finish_reason
+            .as_ref()
+            .and_then(|reason| reason.note())
+            .into_iter()
+            .collect();
+        if !self.safety.is_empty() {
+            notes.push(format!("safety ratings : {}", self.safety.join(", ")));
+        }
+        notes
+    }
+
+    pub fn text(&self) -> String {
+        self.content
+            .parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A model provider.
+///
+/// Sessions are always stored in the `context` format; a backend translates a
+/// conversation into its own wire format and turns the provider's answer back
+/// into a model `Content`, so adding a provider never touches session storage.
+pub trait Backend: Send + Sync {
+    /// Name of the model answering, as recorded in the usage of a session.
+    fn model(&self) -> &str;
+
+    /// Builds the request that sends the conversation, streamed as
+    /// server-sent events when `stream` is set.
+    fn request(&self, context: &Context, stream: bool) -> Request;
+
+    /// Returns the text carried by a single streamed event.
+    fn stream_text(&self, event: &Value) -> String;
+
+    /// Builds a complete response out of the last streamed event and the
+    /// whole streamed text, so it can be handed to `parse` like any other.
+    fn stream_response(&self, last: Value, text: &str) -> Value;
+
+    /// Reads the model turn out of a response body.
+    fn parse(&self, response: &Value) -> Option<Reply>;
+
+    /// Explains why a request failed with `status`, or why a response holds
+    /// no answer.
+    fn error(&self, status: u32, response: &Value) -> ApiError;
+
+    /// Builds the request that starts a resumable upload of a file of `size`
+    /// bytes; the bytes are then sent to the url returned in its headers.
+    fn upload_request(&self, name: &str, mime_type: &str, size: u64) -> Request;
+
+    /// Builds the request sending the bytes of a file, from the headers of the
+    /// response to `upload_request`; `None` when they name no upload url.
+    fn finish_upload_request(&self, start_headers: &[String]) -> Option<Request>;
+
+    /// Builds the request reporting the state of an uploaded file.
+    fn file_request(&self, name: &str) -> Request;
+
+    /// Reads an uploaded file out of an upload or file state response.
+    fn uploaded_file(&self, response: &Value) -> Option<RemoteFile>;
+}
+
+/// Creates the backend chosen in the config, using the model and generation
+/// parameters resolved in `flags`.
+pub fn from_config(config: &Config, flags: &Flags) -> Result<Arc<dyn Backend>> {
+    match config.backend.as_deref().unwrap_or("gemini") {
+        "gemini" => Ok(Arc::new(gemini::Gemini::new(config, flags))),
+        other => {
+            println!("{} : unknown backend `{}`", "Error".red(), other);
+            Err(ErrorKind::Other.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FinishReason;
+
+    #[test]
+    fn finish_reasons_are_stored_as_the_api_names_them() {
+        let reasons = [
+            FinishReason::Stop,
+            FinishReason::MaxTokens,
+            FinishReason::Other("BLOCKLIST".into()),
+        ];
+        let json = serde_json::to_string(&reasons).unwrap();
+        assert_eq!(json, r#"["STOP","MAX_TOKENS","BLOCKLIST"]"#);
+        let read: Vec<FinishReason> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read, reasons);
+    }
+}
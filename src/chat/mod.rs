@@ -0,0 +1,239 @@
+use crate::api::error::ApiError;
+use crate::backend::{self, Backend};
+use crate::config::configfile::Config;
+use crate::config::display::{self, DisplayMode};
+use crate::config::flags::Flags;
+use crate::{api, context, usage};
+use colored::*;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::fs;
+use std::io::{Error, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+const HELP: &str = r#"
+  /image <path>     attach an image to the next prompt (can be repeated)
+  /attach <path>    upload a PDF, audio or video file with the next prompt
+  /session <name>   switch to another session (created if needed)
+  /clear            forget the conversation of the current session
+  /continue         get the rest of an answer cut off by the token limit
+  /compact [turns]  summarize all but the last turns (2 by default)
+  /save [path]      save the session transcript as markdown
+  /usage            show the tokens used by the current session
+  /model [name]     show or change the model
+  /help             show this help
+  /exit             leave the chat
+
+  End a line with \ to continue the prompt on the next line.
+"#;
+
+/// Keeps a session open and answers prompts until the user leaves.
+///
+/// Every turn goes through `api::api_call` and `api::write_result` exactly like
+/// a one-shot invocation, so the session can be continued either way.
+pub fn run(mut flags: Flags, config: &Config) -> Result<()> {
+    // answers are shown inline, an external viewer would take over the terminal
+    if matches!(
+        flags.display_mode,
+        DisplayMode::Config | DisplayMode::Custom
+    ) {
+        flags.display_mode = DisplayMode::Defualt;
+    }
+
+    let mut backend = backend::from_config(config, &flags)?;
+    let mut editor = DefaultEditor::new().map_err(Error::other)?;
+    let history = history_path();
+    let _ = editor.load_history(&history);
+
+    println!(
+        "Chatting in session {}, type {} for the commands.",
+        session_name(&flags).yellow(),
+        "/help".green()
+    );
+
+    while let Some(input) = read_input(&mut editor)? {
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+
+        if let Some(command) = input.strip_prefix('/') {
+            let (name, arg) = match command.split_once(char::is_whitespace) {
+                Some((name, arg)) => (name, arg.trim()),
+                None => (command, ""),
+            };
+            if matches!(name, "exit" | "quit") {
+                break;
+            }
+            if let Err(err) = run_command(name, arg, &mut flags, config, &mut backend) {
+                report(&err);
+            }
+            continue;
+        }
+
+        flags.query = Some(input.to_string());
+        if let Err(err) = ask(&mut flags, config, &backend) {
+            report(&err);
+        }
+        flags.images.clear();
+        flags.attachments.clear();
+    }
+
+    let _ = editor.save_history(&history);
+    return Ok(());
+}
+
+/// Prints an error that ended a turn, unless it was already explained when it
+/// happened.
+fn report(err: &Error) {
+    if err.get_ref().is_none_or(|err| !err.is::<ApiError>()) {
+        eprintln!("{} : {}", "Error".red(), err);
+    }
+}
+
+fn ask(flags: &mut Flags, config: &Config, backend: &Arc<dyn Backend>) -> Result<()> {
+    if !flags.ignore_budget {
+        usage::check_budget(config)?;
+    }
+    api::api_call(flags.clone(), backend.clone())?;
+    api::write_result(flags, backend.as_ref())?;
+    return display::display(flags, config);
+}
+
+fn run_command(
+    name: &str,
+    arg: &str,
+    flags: &mut Flags,
+    config: &Config,
+    backend: &mut Arc<dyn Backend>,
+) -> Result<()> {
+    match name {
+        "help" => println!("{}", HELP),
+        "image" => {
+            if arg.is_empty() {
+                println!("Usage : /image <path>");
+                return Ok(());
+            }
+            flags.attach_image(arg)?;
+            println!("{} will be sent with the next prompt.", arg.green());
+        }
+        "attach" => {
+            if arg.is_empty() {
+                println!("Usage : /attach <path>");
+                return Ok(());
+            }
+            flags.attach(arg)?;
+            println!("{} will be uploaded with the next prompt.", arg.green());
+        }
+        "session" => {
+            if arg.is_empty() {
+                println!("Current session : {}", session_name(flags).yellow());
+                return Ok(());
+            }
+            flags.switch_session(&config.base_path(), arg)?;
+            println!("Switched to session {}.", arg.yellow());
+        }
+        "clear" => {
+            context::clear(&flags.savedir)?;
+            println!(
+                "The conversation of {} was cleared.",
+                session_name(flags).yellow()
+            );
+        }
+        "continue" => {
+            flags.query = Some(api::CONTINUE_PROMPT.into());
+            ask(flags, config, backend)?;
+        }
+        "compact" => {
+            if !flags.ignore_budget {
+                usage::check_budget(config)?;
+            }
+            let keep = arg.parse().unwrap_or(api::compact::DEFAULT_KEEP);
+            match api::compact::compact(flags, backend.as_ref(), keep)? {
+                0 => println!("The conversation is too short to be compacted."),
+                count => println!("{} turns were replaced by a summary.", count),
+            }
+        }
+        "usage" => {
+            let session = usage::load(&flags.savedir)?;
+            let total = session.total;
+            println!(
+                "{} prompt + {} output = {} tokens",
+                total.prompt_tokens, total.candidate_tokens, total.total_tokens
+            );
+            if let Some(cost) = usage::session_cost(config, &session) {
+                println!("Estimated cost : {:.4}", cost);
+            }
+        }
+        "save" => {
+            let path = if arg.is_empty() {
+                format!("{}.md", session_name(flags))
+            } else {
+                arg.to_string()
+            };
+            fs::copy(flags.resultfile.clone().unwrap(), &path)?;
+            println!("Transcript saved to {}.", path.green());
+        }
+        "model" => {
+            if arg.is_empty() {
+                println!(
+                    "Current model : {}",
+                    flags.model.clone().unwrap_or("default".into()).yellow()
+                );
+                return Ok(());
+            }
+            flags.model = Some(arg.to_string());
+            *backend = backend::from_config(config, flags)?;
+            println!("Now using {}.", arg.yellow());
+        }
+        _ => println!(
+            "Unknown command /{}, type {} for the commands.",
+            name,
+            "/help".green()
+        ),
+    }
+    return Ok(());
+}
+
+/// Reads a prompt, joining the lines that end with a backslash.
+/// Returns `None` once the input is closed.
+fn read_input(editor: &mut DefaultEditor) -> Result<Option<String>> {
+    let mut input = String::new();
+    let mut prompt = "> ";
+    loop {
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if let Some(line) = line.strip_suffix('\\') {
+                    input.push_str(line);
+                    input.push('\n');
+                    prompt = ". ";
+                    continue;
+                }
+                input.push_str(&line);
+                if !input.trim().is_empty() {
+                    let _ = editor.add_history_entry(input.as_str());
+                }
+                return Ok(Some(input));
+            }
+            Err(ReadlineError::Interrupted) => return Ok(Some(String::new())),
+            Err(ReadlineError::Eof) => return Ok(None),
+            Err(err) => return Err(Error::other(err)),
+        }
+    }
+}
+
+fn session_name(flags: &Flags) -> String {
+    Path::new(&flags.savedir)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn history_path() -> String {
+    let homedir = dir::home_dir().unwrap();
+    format!(
+        "{}/.config/terminator/chat_history",
+        homedir.to_str().unwrap()
+    )
+}
@@ -0,0 +1,236 @@
+use clap::{Args, Parser, Subcommand};
+
+#[derive(Parser)]
+#[command(
+    name = "terminator",
+    version,
+    about = "A command line client for Google's Gemini",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub ask: AskArgs,
+}
+
+impl Cli {
+    /// Returns the command to run, `ask` when none was given.
+    pub fn into_command(self) -> Command {
+        self.command.unwrap_or(Command::Ask(self.ask))
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Send a prompt and show the answer (the default command)
+    Ask(AskArgs),
+    /// Keep a session open and chat interactively
+    Chat(ChatArgs),
+    /// Ask for the rest of an answer cut off by the output token limit
+    Continue(ChatArgs),
+    /// Create the config file, or show the current one
+    Config {
+        #[command(subcommand)]
+        action: Option<ConfigAction>,
+    },
+    /// Manage the stored sessions
+    Session {
+        #[command(subcommand)]
+        action: SessionAction,
+    },
+    /// Show the tokens used, by day and model
+    Usage {
+        /// Only count the requests of this session
+        #[arg(short, long, value_name = "NAME")]
+        session: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigAction {
+    /// Interactively create the config file
+    Create,
+    /// Print the current config
+    Show,
+}
+
+#[derive(Subcommand)]
+pub enum SessionAction {
+    /// List the sessions in the base directory
+    List,
+    /// Delete a session and everything stored in it
+    Delete {
+        name: String,
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Rename a session
+    Rename { from: String, to: String },
+    /// Replace the older turns of a session with a summary, the full history
+    /// is kept in the session's history directory
+    Compact {
+        /// Session to compact, the default session when not given
+        name: Option<String>,
+        /// Number of recent turns to keep as they are
+        #[arg(long, value_name = "COUNT", default_value_t = 2)]
+        keep: usize,
+        /// Compact even when the budget set in the config is spent
+        #[arg(long)]
+        ignore_budget: bool,
+    },
+    /// Remove the stored images no conversation refers to anymore
+    Gc {
+        /// Only list the images that would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Args)]
+#[group(id = "input", multiple = false)]
+pub struct QueryArgs {
+    /// The prompt to send, optional when input is piped in
+    #[arg(value_name = "PROMPT")]
+    pub text: Option<String>,
+
+    /// The prompt to send, same as the positional argument
+    #[arg(short, long = "prompt", value_name = "PROMPT")]
+    pub prompt: Option<String>,
+}
+
+#[derive(Args)]
+pub struct AskArgs {
+    #[command(flatten)]
+    pub query: QueryArgs,
+
+    /// Image to send along with the prompt (can be repeated)
+    #[arg(short, long = "image", value_name = "PATH")]
+    pub images: Vec<String>,
+
+    /// Text file to send along with the prompt (can be repeated), files can
+    /// also be referenced in the prompt as @path
+    #[arg(long = "file", value_name = "PATH")]
+    pub files: Vec<String>,
+
+    /// PDF, audio or video file to upload along with the prompt (can be
+    /// repeated)
+    #[arg(short, long = "attach", value_name = "PATH")]
+    pub attachments: Vec<String>,
+
+    /// Write the result to this file instead of the session's result.md
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<String>,
+
+    #[command(flatten)]
+    pub session: SessionArgs,
+
+    #[command(flatten)]
+    pub model: ModelArgs,
+
+    #[command(flatten)]
+    pub display: DisplayArgs,
+}
+
+#[derive(Args)]
+pub struct ChatArgs {
+    #[command(flatten)]
+    pub session: SessionArgs,
+
+    #[command(flatten)]
+    pub model: ModelArgs,
+
+    #[command(flatten)]
+    pub display: DisplayArgs,
+}
+
+#[derive(Args)]
+pub struct SessionArgs {
+    /// Session to use, relative to the base directory
+    #[arg(short, long, value_name = "NAME")]
+    pub session: Option<String>,
+
+    /// Store the response and result in the current directory
+    #[arg(long)]
+    pub temp: bool,
+
+    /// Set the system instruction of the session, an empty text removes it
+    #[arg(long, value_name = "TEXT", conflicts_with = "system_file")]
+    pub system: Option<String>,
+
+    /// Set the system instruction of the session from a file
+    #[arg(long, value_name = "PATH")]
+    pub system_file: Option<String>,
+
+    /// Set how strictly the session blocks harmful answers (can be repeated),
+    /// the category is harassment, hate, sexual, dangerous, civic or all and
+    /// the level none, high, medium, low or off; `default` restores the
+    /// defaults of the model
+    #[arg(long, value_name = "CATEGORY=LEVEL")]
+    pub safety: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct ModelArgs {
+    /// Model used to answer
+    #[arg(short, long, value_name = "NAME")]
+    pub model: Option<String>,
+
+    /// Randomness of the answer, between 0 and 2
+    #[arg(long, value_name = "VALUE")]
+    pub temperature: Option<f64>,
+
+    /// Maximum cumulative probability of the tokens considered
+    #[arg(long, value_name = "VALUE")]
+    pub top_p: Option<f64>,
+
+    /// Maximum number of tokens considered
+    #[arg(long, value_name = "COUNT")]
+    pub top_k: Option<u32>,
+
+    /// Maximum number of tokens in the answer
+    #[arg(long, value_name = "COUNT")]
+    pub max_output_tokens: Option<u32>,
+
+    /// Stop generating at this sequence (can be repeated)
+    #[arg(long = "stop", value_name = "TEXT")]
+    pub stop_sequences: Vec<String>,
+
+    /// Number of answers to generate, only 1 is supported
+    #[arg(long, value_name = "COUNT")]
+    pub candidate_count: Option<u32>,
+
+    /// Print the answer while it is generated
+    #[arg(long, conflicts_with = "no_stream")]
+    pub stream: bool,
+
+    /// Wait for the whole answer, even if streaming is enabled in the config
+    #[arg(long)]
+    pub no_stream: bool,
+
+    /// Send the prompt even when the budget set in the config is spent
+    #[arg(long)]
+    pub ignore_budget: bool,
+}
+
+#[derive(Args)]
+#[group(id = "display", multiple = false)]
+pub struct DisplayArgs {
+    /// Print the result file as it is
+    #[arg(long)]
+    pub raw: bool,
+
+    /// Do not show the result
+    #[arg(long)]
+    pub no_display: bool,
+
+    /// Open the result with a command, {} is replaced by the result file
+    #[arg(long, alias = "custom", value_name = "COMMAND")]
+    pub open_with: Option<String>,
+
+    /// Only write the answer to stdout, without spinner, colours or viewer
+    #[arg(short, long)]
+    pub filter: bool,
+}
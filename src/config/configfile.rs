@@ -1,19 +1,58 @@
+use crate::api::redact;
 use crate::utils;
 use colored::*;
 use serde::{Deserialize, Serialize};
 use serde_json::{self, json, Value};
 use std::{
+    collections::HashMap,
     fs,
     io::{self, BufRead, ErrorKind, Result, Write},
     path::Path,
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct Config {
     pub api: String,
     pub basedir: Option<String>,
     pub default_session: Option<String>,
     pub default_viewer: Option<String>,
+    pub stream: Option<bool>,
+    pub backend: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u32>,
+    pub max_output_tokens: Option<u32>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub candidate_count: Option<u32>,
+    pub theme: Option<String>,
+    pub max_file_size: Option<u64>,
+    pub share_images: Option<bool>,
+    pub max_turns: Option<usize>,
+    pub max_context_tokens: Option<u64>,
+    pub pin_first_turn: Option<bool>,
+    pub compact_threshold: Option<u64>,
+    pub prices: Option<HashMap<String, Price>>,
+    pub budget: Option<Budget>,
+    pub retries: Option<u32>,
+    pub connect_timeout: Option<u64>,
+    pub timeout: Option<u64>,
+}
+
+/// Limits on the tokens used, or their estimated cost, across all sessions.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct Budget {
+    pub daily_tokens: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+    pub daily_cost: Option<f64>,
+    pub monthly_cost: Option<f64>,
+}
+
+/// Price of a model, per million tokens.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
 }
 
 impl Config {
@@ -34,12 +73,7 @@ impl Config {
                         let _ = fs::create_dir_all(basedir);
                     }
                 }
-                return Ok(Self {
-                    api: vals.api,
-                    basedir: vals.basedir,
-                    default_session: vals.default_session,
-                    default_viewer: vals.default_viewer,
-                });
+                return Ok(vals);
             } else {
                 println!("{} : failed to parse the config file", "Error".red());
                 return Err(ErrorKind::Other.into());
@@ -63,8 +97,18 @@ impl Config {
         return Err(ErrorKind::Other.into());
     }
 
-    pub fn print(self: &Self) {
-        println!("API KEY : {}", self.api);
+    /// Directory holding the sessions, the current directory when not set.
+    pub fn base_path(&self) -> String {
+        self.basedir.clone().unwrap_or(".".into())
+    }
+
+    /// Directory holding the images shared by the sessions.
+    pub fn image_store(&self) -> String {
+        format!("{}/.images", self.base_path())
+    }
+
+    pub fn print(&self) {
+        println!("API KEY : {}", redact::mask(&self.api));
         println!(
             "BASE DIR : {}",
             self.basedir.clone().unwrap_or("Not Set".to_string())
@@ -75,12 +119,16 @@ impl Config {
                 .clone()
                 .unwrap_or("NOT SET".to_string())
         );
+        println!(
+            "MODEL : {}",
+            self.model.clone().unwrap_or("NOT SET".to_string())
+        );
     }
 }
 
-fn write_config(path: &String, content: &String) -> Result<()> {
-    let mut configfile = utils::overwrite(&path)?;
-    configfile.write_all(&content.clone().into_bytes())?;
+fn write_config(path: &str, content: &str) -> Result<()> {
+    let mut configfile = utils::overwrite(path)?;
+    configfile.write_all(content.as_bytes())?;
     return Ok(());
 }
 
@@ -122,7 +170,7 @@ pub fn create() -> Result<()> {
         api: apikey.trim().into(),
         basedir: None,
         default_session: None,
-        default_viewer: None,
+        ..Default::default()
     };
 
     if basedir.trim() != "" {
@@ -159,14 +207,14 @@ pub fn create() -> Result<()> {
         match ch.trim() {
             "y" | "Y" => {
                 println!("re-writing the config!");
-                write_config(&configpath.to_str().unwrap().into(), &config)?;
+                write_config(configpath.to_str().unwrap(), &config)?;
             }
             _ => {}
         };
     } else {
         println!("writing the config at {}: ", &configpath.to_str().unwrap());
-        write_config(&configpath.to_str().unwrap().into(), &config)?;
+        write_config(configpath.to_str().unwrap(), &config)?;
     }
 
-    std::process::exit(0);
+    return Ok(());
 }
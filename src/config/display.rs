@@ -1,12 +1,9 @@
-use crate::utils;
+use crate::render;
 use crate::Config;
 use crate::Flags;
-use std::ffi::CString;
-use std::io::Write;
-
-extern "C" {
-    pub fn run(file: *const u8);
-}
+use colored::*;
+use std::io::{ErrorKind, Result};
+use std::process::Command;
 
 #[derive(Clone, Copy)]
 pub enum DisplayMode {
@@ -15,59 +12,90 @@ pub enum DisplayMode {
     Config,
     Hidden,
     Custom,
+    Filter,
 }
 
 impl DisplayMode {
-    pub fn cmd(self: &Self, config: &Config, flags: &Flags) -> Option<String> {
+    pub fn cmd(&self, config: &Config, flags: &Flags) -> Option<String> {
         match self {
             DisplayMode::Raw => Some(String::from("cat {}")),
-            DisplayMode::Defualt => Some(String::from("pandoc -t plain {}")),
-            DisplayMode::Config => {
-                if let Some(cmd) = &config.default_viewer {
-                    Some(cmd.into())
-                } else {
-                    None
-                }
-            }
+            DisplayMode::Defualt => None,
+            DisplayMode::Config => config.default_viewer.as_ref().map(|cmd| cmd.into()),
             DisplayMode::Custom => {
                 let cmd = flags.custom_command.clone().unwrap();
                 Some(cmd)
             }
-            DisplayMode::Hidden => None,
+            DisplayMode::Hidden | DisplayMode::Filter => None,
         }
     }
 }
 
-pub fn display(flags: &Flags, config: &Config) {
-    unsafe {
+pub fn display(flags: &Flags, config: &Config) -> Result<()> {
+    // a streamed answer has already been printed while it was generated
+    let streamed = flags.stream
+        && matches!(
+            flags.display_mode,
+            DisplayMode::Defualt | DisplayMode::Raw | DisplayMode::Filter
+        );
+    if !streamed {
         match flags.display_mode {
             DisplayMode::Hidden => {}
+            DisplayMode::Filter => print!("{}", flags.resulttext.clone().unwrap()),
             DisplayMode::Defualt => {
-                let temppath = format!("{}/tempresult.md", &flags.savedir.clone());
-                let mut tempresultfile = utils::overwrite(&temppath).unwrap();
-                let _ = tempresultfile.write_all(&flags.resulttext.clone().unwrap().into_bytes());
-
-                let cmd = CString::new(
-                    flags
-                        .display_mode
-                        .clone()
-                        .cmd(config, flags)
-                        .unwrap()
-                        .replace("{}", &temppath),
+                let theme = config
+                    .theme
+                    .as_deref()
+                    .unwrap_or(render::highlight::DEFAULT_THEME);
+                print!(
+                    "{}",
+                    render::render(&flags.resulttext.clone().unwrap(), theme)
                 );
-                run(cmd.unwrap().to_bytes().as_ptr());
             }
             _ => {
-                let cmd = CString::new(
-                    flags
-                        .display_mode
-                        .clone()
-                        .cmd(config, flags)
-                        .unwrap()
-                        .replace("{}", &flags.resultfile.clone().unwrap()),
-                );
-                run(cmd.unwrap().to_bytes().as_ptr());
+                let cmd = flags.display_mode.cmd(config, flags).unwrap();
+                run(&cmd, &flags.resultfile.clone().unwrap())?;
             }
         }
     }
+    for note in &flags.notes {
+        // keep stdout for the answer in filter mode
+        if flags.filter {
+            eprintln!("{} : {}", "Warning".yellow(), note);
+        } else {
+            println!("{} : {}", "Warning".yellow(), note);
+        }
+    }
+    return Ok(());
+}
+
+/// Runs a viewer command, passing `file` in place of every `{}` placeholder.
+///
+/// The command is split into arguments like a shell would, but it is never
+/// handed to one, so the path is always passed as a single argument.
+fn run(cmd: &str, file: &str) -> Result<()> {
+    let args = match shell_words::split(cmd) {
+        Ok(args) if !args.is_empty() => args,
+        _ => {
+            eprintln!("{} : invalid viewer command `{}`", "Error".red(), cmd);
+            return Err(ErrorKind::InvalidInput.into());
+        }
+    };
+    let args: Vec<String> = args.iter().map(|arg| arg.replace("{}", file)).collect();
+
+    let status = match Command::new(&args[0]).args(&args[1..]).status() {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("{} : could not run `{}` : {}", "Error".red(), args[0], err);
+            return Err(err);
+        }
+    };
+    if !status.success() {
+        eprintln!(
+            "{} : `{}` exited with {}",
+            "Warning".yellow(),
+            args[0],
+            status
+        );
+    }
+    return Ok(());
 }
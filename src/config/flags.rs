@@ -1,17 +1,58 @@
 use colored::Colorize;
 
-use crate::utils;
+use crate::api::retry::Network;
+use crate::api::upload;
+use crate::backend::Generation;
+use crate::context::SafetySetting;
 use crate::{config::configfile::Config, config::display::DisplayMode};
-use std::io::Result;
+use crate::{context, utils};
+use clap::CommandFactory;
+use std::fs;
+use std::io::{self, ErrorKind, IsTerminal, Read, Result};
 
-use super::configfile;
+use super::cli::{AskArgs, Cli, DisplayArgs, ModelArgs, SessionArgs};
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+const SAFETY_CATEGORIES: &[(&str, &str)] = &[
+    ("harassment", "HARM_CATEGORY_HARASSMENT"),
+    ("hate", "HARM_CATEGORY_HATE_SPEECH"),
+    ("sexual", "HARM_CATEGORY_SEXUALLY_EXPLICIT"),
+    ("dangerous", "HARM_CATEGORY_DANGEROUS_CONTENT"),
+    ("civic", "HARM_CATEGORY_CIVIC_INTEGRITY"),
+];
+
+const SAFETY_LEVELS: &[(&str, &str)] = &[
+    ("none", "BLOCK_NONE"),
+    ("high", "BLOCK_ONLY_HIGH"),
+    ("medium", "BLOCK_MEDIUM_AND_ABOVE"),
+    ("low", "BLOCK_LOW_AND_ABOVE"),
+    ("off", "OFF"),
+];
+
+/// An image attached to the next user turn.
+#[derive(Clone)]
+pub struct Image {
+    /// Path of the copy stored in the session, relative to it.
+    pub path: String,
+    pub hash: String,
+    pub mime_type: String,
+}
+
+/// A PDF, audio or video file sent through the file API.
+#[derive(Clone)]
+pub struct Attachment {
+    pub path: String,
+    pub mime_type: String,
+    /// Set once the file is uploaded.
+    pub uri: Option<String>,
+}
 
 #[derive(Clone)]
 
 pub struct Flags {
     pub query: Option<String>,
-    pub image: Option<String>,
-    pub image_path: Option<String>,
+    pub images: Vec<Image>,
     pub responsefile: Option<String>,
     pub resultfile: Option<String>,
     pub resulttext: Option<String>,
@@ -19,139 +60,332 @@ pub struct Flags {
     pub savedir: String,
     pub display_mode: DisplayMode,
     pub temp: bool,
-    pub imghash: Option<String>,
-    pub delete: bool,
+    pub stream: bool,
+    pub model: Option<String>,
+    pub generation: Generation,
+    pub system: Option<String>,
+    pub stdin: Option<String>,
+    pub filter: bool,
+    pub files: Vec<(String, String)>,
+    pub attachments: Vec<Attachment>,
+    pub image_store: Option<String>,
+    pub window: context::Window,
+    pub compact_threshold: Option<u64>,
+    pub ignore_budget: bool,
+    pub network: Network,
+    /// Warnings about how the last answer ended.
+    pub notes: Vec<String>,
 }
-impl Flags {
-    pub fn default() -> Self {
+impl Default for Flags {
+    fn default() -> Self {
         Self {
             query: None,
             display_mode: DisplayMode::Defualt,
-            image: None,
-            image_path: None,
+            images: vec![],
             responsefile: None,
             custom_command: None,
             savedir: "".into(),
             resultfile: None,
             resulttext: None,
-            imghash: None,
             temp: false,
-            delete: false,
+            stream: false,
+            model: None,
+            generation: Generation::default(),
+            system: None,
+            stdin: None,
+            filter: false,
+            files: vec![],
+            attachments: vec![],
+            image_store: None,
+            window: context::Window::default(),
+            compact_threshold: None,
+            ignore_budget: false,
+            network: Network::default(),
+            notes: vec![],
         }
     }
+}
 
-    pub fn parse(config: &Config, args: Vec<String>) -> Result<Self> {
-        let mut flags = Flags::default();
+impl Flags {
+    /// Resolves the flags of the `ask` command: the options shared with `chat`
+    /// plus the prompt, its attachments and the output file.
+    pub fn parse(config: &Config, args: &AskArgs) -> Result<Self> {
+        let mut flags = Flags::new(config, &args.session, &args.model, &args.display)?;
 
-        let basedir: String;
-        let mut session: String;
-        let mut output: String = String::new();
+        flags.query = args.query.text.clone().or(args.query.prompt.clone());
+        // only a prompt typed by the user may pull files in, never piped input
+        // that could ask for any file the user can read
+        let references = flags
+            .query
+            .as_deref()
+            .map(utils::file_references)
+            .unwrap_or_default();
 
-        if args.len() <= 1 {
-            println!("{} : Provide arguments to the program!", "Error".red());
-            std::process::exit(0);
+        let stdin = io::stdin();
+        if !stdin.is_terminal() {
+            let mut input = String::new();
+            stdin.lock().read_to_string(&mut input)?;
+            if !input.trim().is_empty() {
+                flags.stdin = input.into();
+            }
         }
-
-        if args[1] == "config" {
-            configfile::create()?;
+        match (&flags.query, &flags.stdin) {
+            (Some(_), _) => {}
+            // the piped input is the whole prompt
+            (None, Some(_)) => flags.query = flags.stdin.take(),
+            (None, None) => Cli::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "a prompt is required, either as an argument or piped to stdin",
+                )
+                .exit(),
         }
 
-        match &config.basedir {
-            Some(dir) => {
-                basedir = dir.into();
-            }
-            None => {
-                basedir = ".".into();
+        let limit = config.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE);
+        for path in args.files.iter().chain(references.iter()) {
+            if flags.files.iter().any(|(attached, _)| attached == path) {
+                continue;
             }
+            let text = utils::read_text_file(path, limit)?;
+            flags.files.push((path.clone(), text));
         }
 
-        match &config.default_session {
-            Some(sess) => {
-                session = sess.into();
-            }
-            None => session = "".into(),
+        for image_path in &args.images {
+            flags.attach_image(image_path)?;
+        }
+
+        for path in &args.attachments {
+            flags.attach(path)?;
+        }
+
+        if let Some(output) = &args.output {
+            flags.resultfile = Some(output.clone());
         }
 
-        if let Some(_) = &config.default_viewer {
+        Ok(flags)
+    }
+
+    /// Resolves the options shared by `ask` and `chat`, on top of the values
+    /// from the config, and opens the session.
+    pub fn new(
+        config: &Config,
+        session: &SessionArgs,
+        model: &ModelArgs,
+        display: &DisplayArgs,
+    ) -> Result<Self> {
+        let mut flags = Flags::default();
+
+        if config.default_viewer.is_some() {
             flags.display_mode = DisplayMode::Config
         }
+        if display.raw {
+            flags.display_mode = DisplayMode::Raw;
+        }
+        if display.no_display {
+            flags.display_mode = DisplayMode::Hidden;
+        }
+        if let Some(command) = &display.open_with {
+            flags.display_mode = DisplayMode::Custom;
+            flags.custom_command = command.clone().into();
+        }
+        if display.filter {
+            flags.display_mode = DisplayMode::Filter;
+            flags.filter = true;
+            colored::control::set_override(false);
+        }
 
-        for (index, flag) in args.iter().enumerate() {
-            match flag.as_str() {
-                "--output" => {
-                    output = args[index + 1].clone().into();
-                }
-                "--no-display" => {
-                    flags.display_mode = DisplayMode::Hidden;
-                }
-                "--prompt" => {
-                    flags.query = args[index + 1].clone().into();
-                }
-                "--raw" => {
-                    flags.display_mode = DisplayMode::Raw;
-                }
-                "--image" => {
-                    flags.image_path = args[index + 1].clone().into();
-                    let (hash, image_data): (String, String) =
-                        utils::read_image(&flags.image_path.clone().unwrap())?.into();
-                    flags.image = image_data.into();
-                    flags.imghash = hash.into();
-                }
-                "--temp" => {
-                    flags.temp = true;
-                }
-                "--open-with" => {
-                    flags.display_mode = DisplayMode::Custom;
-                    flags.custom_command = args[index + 1].clone().into();
-                }
-                "--session" => {
-                    session = args[index + 1].clone().into();
-                }
-                "--custom" => {
-                    flags.display_mode = DisplayMode::Custom;
-                    flags.custom_command = args[index + 1].clone().into()
+        flags.stream = (config.stream.unwrap_or(false) || model.stream) && !model.no_stream;
+        flags.model = model.model.clone().or(config.model.clone());
+        flags.ignore_budget = model.ignore_budget;
+        flags.network = Network::from_config(config);
+        flags.generation = Generation {
+            temperature: model.temperature.or(config.temperature),
+            top_p: model.top_p.or(config.top_p),
+            top_k: model.top_k.or(config.top_k),
+            max_output_tokens: model.max_output_tokens.or(config.max_output_tokens),
+            stop_sequences: if model.stop_sequences.is_empty() {
+                config.stop_sequences.clone()
+            } else {
+                model.stop_sequences.clone().into()
+            },
+            candidate_count: model.candidate_count.or(config.candidate_count),
+        };
+        // only the first answer is read, so more would be paid for and lost
+        if flags
+            .generation
+            .candidate_count
+            .is_some_and(|count| count > 1)
+        {
+            eprintln!(
+                "{} : a {} above 1 is not supported, only one answer can be shown",
+                "Error".red(),
+                "candidate_count".green()
+            );
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        flags.window = context::Window {
+            max_turns: config.max_turns,
+            max_tokens: config.max_context_tokens,
+            pin_first: config.pin_first_turn.unwrap_or(false),
+        };
+
+        flags.compact_threshold = config.compact_threshold;
+
+        if config.share_images.unwrap_or(false) {
+            flags.image_store = config.image_store().into();
+        }
+
+        flags.system = session.system.clone();
+        if let Some(path) = &session.system_file {
+            match fs::read_to_string(path) {
+                Ok(text) => flags.system = text.into(),
+                Err(err) => {
+                    eprintln!(
+                        "{} : could not read the system instruction from {}",
+                        "Error".red(),
+                        path.yellow()
+                    );
+                    return Err(err);
                 }
-                "--delete" => flags.delete = true,
-                _ => {}
             }
         }
 
-        let session_path = format!("{}/{}", &basedir, &session);
-        let session = session
-            .split(' ')
-            .map(|x| x.to_string())
-            .fold(String::new(), |acc, x| acc + &x)
-            .to_string();
+        let name = session
+            .session
+            .clone()
+            .or(config.default_session.clone())
+            .unwrap_or_default();
+        flags.switch_session(&config.base_path(), &name)?;
 
-        if flags.delete {
-            utils::delete_session(&session_path, &session)?;
+        if let Some(system) = &flags.system {
+            context::set_system_instruction(&flags.savedir, system)?;
+        }
+        if session.safety.iter().any(|arg| arg == "default") {
+            context::set_safety_settings(&flags.savedir, &[])?;
+        }
+        let mut settings = vec![];
+        for arg in session.safety.iter().filter(|arg| *arg != "default") {
+            settings.extend(safety_settings(arg)?);
+        }
+        if !settings.is_empty() {
+            context::set_safety_settings(&flags.savedir, &settings)?;
         }
 
-        flags.savedir = session_path.clone();
-        utils::make_session(&flags)?;
-
-        if !flags.temp {
-            flags.responsefile = format!("{}/response.json", &session_path).into();
-            flags.resultfile = format!("{}/result.md", &session_path).into();
-        } else {
+        flags.temp = session.temp;
+        if flags.temp {
             flags.responsefile = "response.json".to_string().into();
-            flags.responsefile = "result.md".to_string().into();
+            flags.resultfile = "result.md".to_string().into();
         }
 
-        if let Some(image_path) = &flags.image_path.clone() {
-            flags.image_path = utils::copy_image(
-                image_path,
-                &flags.savedir.clone(),
-                &flags.imghash.clone().unwrap(),
-            )?
-            .into();
-            println!("{}", &flags.image_path.clone().unwrap());
+        Ok(flags)
+    }
+
+    /// Copies an image into the session and adds it to the next user turn.
+    pub fn attach_image(&mut self, source: &str) -> Result<()> {
+        let (hash, mime_type) = utils::read_image(source)?;
+        if self.images.iter().any(|image| image.hash == hash) {
+            return Ok(());
         }
+        let path = utils::copy_image(
+            source,
+            &self.savedir,
+            &hash,
+            mime_type,
+            self.image_store.as_deref(),
+        )?;
+        self.images.push(Image {
+            path,
+            hash,
+            mime_type: mime_type.into(),
+        });
+        Ok(())
+    }
 
-        if output != "" {
-            flags.resultfile = Some(output);
+    /// Adds a file to upload with the next user turn, checking that it can be
+    /// sent before anything goes over the network.
+    pub fn attach(&mut self, path: &str) -> Result<()> {
+        if let Err(err) = fs::metadata(path) {
+            eprintln!(
+                "{} : could not read {} : {}",
+                "Error".red(),
+                path.yellow(),
+                err
+            );
+            return Err(err);
         }
+        let mime_type = match upload::media_type(path) {
+            Some(mime_type) => mime_type,
+            None => {
+                eprintln!(
+                    "{} : {} is not a supported PDF, audio, video or image file",
+                    "Error".red(),
+                    path.yellow()
+                );
+                return Err(ErrorKind::InvalidInput.into());
+            }
+        };
+        self.attachments.push(Attachment {
+            path: path.into(),
+            mime_type: mime_type.into(),
+            uri: None,
+        });
+        Ok(())
+    }
 
-        Ok(flags)
+    /// Points the flags at another session, creating it when needed.
+    pub fn switch_session(&mut self, basedir: &str, session: &str) -> Result<()> {
+        self.savedir = format!("{}/{}", basedir, session);
+        utils::make_session(self)?;
+        self.responsefile = format!("{}/response.json", &self.savedir).into();
+        self.resultfile = format!("{}/result.md", &self.savedir).into();
+        Ok(())
+    }
+}
+
+/// Reads a `CATEGORY=LEVEL` safety setting, `all` setting every category.
+fn safety_settings(arg: &str) -> Result<Vec<SafetySetting>> {
+    let invalid = || {
+        eprintln!(
+            "{} : invalid safety setting `{}`, expected CATEGORY=LEVEL with a category among {} and a level among {}",
+            "Error".red(),
+            arg.yellow(),
+            SAFETY_CATEGORIES
+                .iter()
+                .map(|(name, _)| *name)
+                .chain(["all"])
+                .collect::<Vec<_>>()
+                .join(", "),
+            SAFETY_LEVELS
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        io::Error::from(ErrorKind::InvalidInput)
+    };
+    let (category, level) = arg.split_once('=').ok_or_else(invalid)?;
+    let threshold = SAFETY_LEVELS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(level.trim()))
+        .map(|(_, threshold)| threshold.to_string())
+        .ok_or_else(invalid)?;
+    let categories: Vec<&str> = SAFETY_CATEGORIES
+        .iter()
+        .filter(|(name, _)| {
+            category.trim().eq_ignore_ascii_case("all")
+                || name.eq_ignore_ascii_case(category.trim())
+        })
+        .map(|(_, category)| *category)
+        .collect();
+    if categories.is_empty() {
+        return Err(invalid());
     }
+    Ok(categories
+        .into_iter()
+        .map(|category| SafetySetting {
+            category: category.into(),
+            threshold: threshold.clone(),
+        })
+        .collect())
 }
@@ -1,3 +1,4 @@
+pub mod cli;
 pub mod configfile;
 pub mod display;
 pub mod flags;
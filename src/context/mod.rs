@@ -3,9 +3,15 @@ use crate::utils;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::io::{Read, Result, Write};
+use std::path::Path;
 
+#[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Context {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub systemInstruction: Option<Instruction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub safetySettings: Vec<SafetySetting>,
     pub contents: Vec<Content>,
 }
 
@@ -22,12 +28,28 @@ pub struct Content {
     pub role: Role,
 }
 
+/// How likely to be harmful an answer may be before it is blocked, for one
+/// category of harm.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SafetySetting {
+    pub category: String,
+    pub threshold: String,
+}
+
+/// Persona or rules the model follows for the whole session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Instruction {
+    pub parts: Vec<Part>,
+}
+
 #[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 pub enum Part {
     Text { text: String },
     InlineData { inlineData: InlineData },
+    FileData { fileData: FileData },
+    Image { image: ImageRef },
 }
 
 #[allow(non_snake_case)]
@@ -37,71 +59,293 @@ pub struct InlineData {
     pub data: String,
 }
 
-pub fn add_user_context_without_image(flags: &Flags) -> Result<()> {
-    let contextpath = format!("{}/context.json", &flags.savedir.clone());
+/// A file uploaded through the provider's file API.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileData {
+    pub mimeType: String,
+    pub fileUri: String,
+}
+
+/// An image stored in the session, kept in `context.json` in place of its
+/// content and turned into inline data when a request is built.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageRef {
+    pub mimeType: String,
+    /// Path of the image, relative to the session.
+    pub path: String,
+}
+
+/// Limits on the history sent with a prompt, the oldest turns beyond them
+/// are left out of the request but stay in the session.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Window {
+    /// Number of earlier turns to keep.
+    pub max_turns: Option<usize>,
+    /// Estimated number of tokens the whole request may use.
+    pub max_tokens: Option<u64>,
+    /// Always keep the first turn, on top of the other limits.
+    pub pin_first: bool,
+}
+
+/// Tokens counted for an image, audio or video part by the local estimate.
+const MEDIA_TOKENS: u64 = 258;
+
+pub fn load(savedir: &str) -> Result<Context> {
+    let contextpath = format!("{}/context.json", savedir);
     let mut contextfile = utils::open(&contextpath)?;
     let mut contexttext = String::new();
     contextfile.read_to_string(&mut contexttext)?;
-    let mut context: Context = serde_json::from_str(&contexttext)?;
-    context.contents.push(Content {
-        parts: vec![Part::Text {
-            text: flags.query.clone().unwrap(),
-        }],
-        role: Role::User,
-    });
+    let context: Context = serde_json::from_str(&contexttext)?;
+    return Ok(context);
+}
+
+pub fn save(savedir: &str, context: &Context) -> Result<()> {
+    let contextpath = format!("{}/context.json", savedir);
     let mut contextfile = utils::overwrite(&contextpath)?;
-    contextfile.write(&json!(context).to_string().into_bytes())?;
+    contextfile.write_all(&json!(context).to_string().into_bytes())?;
     return Ok(());
 }
 
+/// Builds the user turn for the prompt: its text, the piped input, the files,
+/// the images and the uploaded attachments, each as its own part.
+pub fn user_content(flags: &Flags) -> Content {
+    let mut parts = vec![Part::Text {
+        text: flags.query.clone().unwrap(),
+    }];
+    if let Some(stdin) = &flags.stdin {
+        parts.push(Part::Text {
+            text: stdin.clone(),
+        });
+    }
+    for (path, text) in &flags.files {
+        parts.push(Part::Text {
+            text: file_part(path, text),
+        });
+    }
+    for image in &flags.images {
+        parts.push(Part::Image {
+            image: ImageRef {
+                mimeType: image.mime_type.clone(),
+                path: image.path.clone(),
+            },
+        });
+    }
+    for attachment in &flags.attachments {
+        if let Some(uri) = &attachment.uri {
+            parts.push(Part::FileData {
+                fileData: FileData {
+                    mimeType: attachment.mime_type.clone(),
+                    fileUri: uri.clone(),
+                },
+            });
+        }
+    }
+    Content {
+        parts,
+        role: Role::User,
+    }
+}
+
+/// Replaces the stored image references of a conversation with the content of
+/// the images, so it can be sent.
+pub fn materialize(savedir: &str, context: &mut Context) -> Result<()> {
+    for content in context.contents.iter_mut() {
+        for part in content.parts.iter_mut() {
+            if let Part::Image { image } = part {
+                let path = Path::new(savedir).join(&image.path);
+                let data = utils::encode_image(&path.to_string_lossy())?;
+                *part = Part::InlineData {
+                    inlineData: InlineData {
+                        mimeType: image.mimeType.clone(),
+                        data,
+                    },
+                };
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Roughly estimates the tokens of some parts, about four characters a token.
+pub fn estimate_tokens(parts: &[Part]) -> u64 {
+    parts
+        .iter()
+        .map(|part| match part {
+            Part::Text { text } => (text.len() as u64).div_ceil(4),
+            _ => MEDIA_TOKENS,
+        })
+        .sum()
+}
+
+/// Groups a conversation into turns, a user content followed by the answers
+/// to it.
+pub fn turns(contents: Vec<Content>) -> Vec<Vec<Content>> {
+    let mut turns: Vec<Vec<Content>> = vec![];
+    for content in contents {
+        match (&content.role, turns.last_mut()) {
+            (Role::Model, Some(turn)) => turn.push(content),
+            _ => turns.push(vec![content]),
+        }
+    }
+    return turns;
+}
+
+/// Drops the oldest turns of the history that do not fit in the window,
+/// returning how many were dropped. The last content, the new prompt, is
+/// always kept.
+pub fn apply_window(context: &mut Context, window: &Window) -> usize {
+    if window.max_turns.is_none() && window.max_tokens.is_none() {
+        return 0;
+    }
+    let prompt = match context.contents.pop() {
+        Some(prompt) => prompt,
+        None => return 0,
+    };
+
+    let mut turns = turns(std::mem::take(&mut context.contents));
+    let pinned = if window.pin_first && !turns.is_empty() {
+        Some(turns.remove(0))
+    } else {
+        None
+    };
+
+    let mut dropped = 0;
+    if let Some(max_turns) = window.max_turns {
+        if turns.len() > max_turns {
+            dropped = turns.len() - max_turns;
+            turns.drain(..dropped);
+        }
+    }
+    if let Some(max_tokens) = window.max_tokens {
+        let tokens = |turn: &Vec<Content>| {
+            turn.iter()
+                .map(|content| estimate_tokens(&content.parts))
+                .sum::<u64>()
+        };
+        let system = context
+            .systemInstruction
+            .as_ref()
+            .map(|instruction| estimate_tokens(&instruction.parts))
+            .unwrap_or(0);
+        let mut total = system
+            + estimate_tokens(&prompt.parts)
+            + pinned.as_ref().map(tokens).unwrap_or(0)
+            + turns.iter().map(tokens).sum::<u64>();
+        while total > max_tokens && !turns.is_empty() {
+            total -= tokens(&turns.remove(0));
+            dropped += 1;
+        }
+    }
+
+    context.contents = pinned.into_iter().chain(turns).flatten().collect();
+    context.contents.push(prompt);
+    return dropped;
+}
+
+/// Returns the paths of the stored images the conversation refers to.
+pub fn image_paths(context: &Context) -> Vec<String> {
+    context
+        .contents
+        .iter()
+        .flat_map(|content| content.parts.iter())
+        .filter_map(|part| match part {
+            Part::Image { image } => Some(image.path.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Labels an attached file and fences its content, with a fence longer than
+/// any run of backticks inside it.
+fn file_part(path: &str, text: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat(longest.max(2) + 1);
+    let lang = Path::new(path)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!(
+        "File `{}`:\n{}{}\n{}\n{}",
+        path,
+        fence,
+        lang,
+        text.trim_end(),
+        fence
+    )
+}
+
+pub fn add_user_context(flags: &Flags) -> Result<()> {
+    let mut context = load(&flags.savedir)?;
+    context.contents.push(user_content(flags));
+    return save(&flags.savedir, &context);
+}
+
 pub fn add_model_context(flags: &Flags, data: String) -> Result<()> {
-    let contextpath = format!("{}/context.json", &flags.savedir.clone());
-    let mut contextfile = utils::open(&contextpath)?;
-    let mut contexttext = String::new();
-    contextfile.read_to_string(&mut contexttext)?;
-    let mut context: Context = serde_json::from_str(&contexttext)?;
+    let mut context = load(&flags.savedir)?;
     context.contents.push(Content {
         parts: vec![Part::Text { text: data }],
         role: Role::Model,
     });
-    let mut contextfile = utils::overwrite(&contextpath)?;
-    contextfile.write(&json!(context).to_string().into_bytes())?;
-    return Ok(());
+    return save(&flags.savedir, &context);
 }
 
-pub fn add_user_context_with_image(flags: &Flags) -> Result<()> {
-    let contextpath = format!("{}/context.json", &flags.savedir.clone());
-    let mut contextfile = utils::open(&contextpath)?;
-    let mut contexttext = String::new();
-    contextfile.read_to_string(&mut contexttext)?;
-    let mut context: Context = serde_json::from_str(&contexttext)?;
-    context.contents.push(Content {
-        parts: vec![
-            Part::Text {
-                text: flags.query.clone().unwrap(),
-            },
-            Part::InlineData {
-                inlineData: InlineData {
-                    mimeType: format!(
-                        "image/{}",
-                        flags.image_path.clone().unwrap().split(".").last().unwrap()
-                    )
-                    .to_string(),
-                    data: flags.image.clone().unwrap(),
-                },
-            },
-        ],
-        role: Role::User,
-    });
-    let mut contextfile = utils::overwrite(&contextpath)?;
-    contextfile.write(&json!(context).to_string().into_bytes())?;
-    return Ok(());
+/// Removes the last user turn of the session, when no answer follows it.
+pub fn pop_user_content(savedir: &str) -> Result<()> {
+    let mut context = load(savedir)?;
+    if matches!(context.contents.last(), Some(content) if matches!(content.role, Role::User)) {
+        context.contents.pop();
+    }
+    return save(savedir, &context);
 }
 
-pub fn initialize_context(savedir: &String) -> Result<()> {
-    let context = Context { contents: vec![] };
-    let contextpath = format!("{}/context.json", savedir);
-    let mut contextfile = utils::overwrite(&contextpath)?;
-    contextfile.write(&json!(context).to_string().into_bytes())?;
-    return Ok(());
+/// Sets the safety settings of the session, replacing those of the same
+/// categories; an empty list restores the defaults of the model.
+pub fn set_safety_settings(savedir: &str, settings: &[SafetySetting]) -> Result<()> {
+    let mut context = load(savedir)?;
+    if settings.is_empty() {
+        context.safetySettings.clear();
+    }
+    for setting in settings {
+        context
+            .safetySettings
+            .retain(|known| known.category != setting.category);
+        context.safetySettings.push(setting.clone());
+    }
+    return save(savedir, &context);
+}
+
+/// Replaces the system instruction of the session, an empty text removes it.
+pub fn set_system_instruction(savedir: &str, text: &str) -> Result<()> {
+    let mut context = load(savedir)?;
+    context.systemInstruction = if text.trim().is_empty() {
+        None
+    } else {
+        Some(Instruction {
+            parts: vec![Part::Text { text: text.into() }],
+        })
+    };
+    return save(savedir, &context);
+}
+
+/// Forgets the conversation of the session while keeping its system instruction.
+pub fn clear(savedir: &str) -> Result<()> {
+    let mut context = load(savedir)?;
+    context.contents.clear();
+    return save(savedir, &context);
+}
+
+pub fn initialize_context(savedir: &str) -> Result<()> {
+    let context = Context {
+        systemInstruction: None,
+        safetySettings: vec![],
+        contents: vec![],
+    };
+    return save(savedir, &context);
 }
@@ -1,27 +1,130 @@
+#![allow(clippy::needless_return)]
+
 pub mod api;
+pub mod backend;
+pub mod chat;
 pub mod config;
 pub mod context;
+pub mod render;
+pub mod usage;
 pub mod utils;
 
-use config::configfile::Config;
-use std::env;
-use std::io::Result;
+use clap::Parser;
+use colored::*;
+use config::cli::{Cli, Command, ConfigAction, SessionAction};
+use config::configfile::{self, Config};
+use std::io::{ErrorKind, Result};
+use std::path::Path;
+use std::process;
+
+use api::error::ApiError;
+use api::retry::Network;
 
 use config::flags::Flags;
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+fn main() {
+    if let Err(err) = run() {
+        // API errors are already explained when they happen
+        if err.get_ref().is_none_or(|err| !err.is::<ApiError>()) {
+            eprintln!("Error: {}", api::redact::text(&format!("{:?}", err)));
+        }
+        process::exit(api::error::exit_code(&err));
+    }
+}
+
+fn run() -> Result<()> {
+    let command = Cli::parse().into_command();
+
+    if let Command::Config { action } = &command {
+        return match action {
+            None | Some(ConfigAction::Create) => configfile::create(),
+            Some(ConfigAction::Show) => {
+                Config::parse()?.print();
+                Ok(())
+            }
+        };
+    }
+
     let userconf = Config::parse()?;
-    let mut flags = Flags::parse(&userconf, args)?;
-    let api = &userconf.api;
-    let response_status = api::api_call(flags.clone(), api.into())?;
-
-    if response_status >= 200 || response_status < 300 {
-        api::write_result(&mut flags)?;
-        config::display::display(&flags, &userconf);
-    } else {
-        println!("Request Failed");
+    match command {
+        Command::Chat(args) => {
+            let flags = Flags::new(&userconf, &args.session, &args.model, &args.display)?;
+            return chat::run(flags, &userconf);
+        }
+        Command::Session { action } => return session(action, &userconf),
+        Command::Usage { session } => return usage::report(&userconf, session.as_deref()),
+        Command::Ask(args) => return ask(Flags::parse(&userconf, &args)?, &userconf),
+        Command::Continue(args) => {
+            let mut flags = Flags::new(&userconf, &args.session, &args.model, &args.display)?;
+            flags.query = Some(api::CONTINUE_PROMPT.into());
+            return ask(flags, &userconf);
+        }
+        Command::Config { .. } => {}
     }
 
     Ok(())
 }
+
+fn ask(mut flags: Flags, config: &Config) -> Result<()> {
+    if !flags.ignore_budget {
+        usage::check_budget(config)?;
+    }
+    let backend = backend::from_config(config, &flags)?;
+    api::api_call(flags.clone(), backend.clone())?;
+    api::write_result(&mut flags, backend.as_ref())?;
+    return config::display::display(&flags, config);
+}
+
+fn session(action: SessionAction, config: &Config) -> Result<()> {
+    let basedir = config.base_path();
+    match action {
+        SessionAction::List => {
+            let default = config.default_session.clone().unwrap_or_default();
+            for name in utils::list_sessions(&basedir)? {
+                if name == default {
+                    println!("{} {}", name.green(), "(default)".dimmed());
+                } else {
+                    println!("{}", name);
+                }
+            }
+        }
+        SessionAction::Delete { name, yes } => {
+            utils::delete_session(&format!("{}/{}", basedir, name), &name, yes)?;
+        }
+        SessionAction::Rename { from, to } => utils::rename_session(&basedir, &from, &to)?,
+        SessionAction::Compact {
+            name,
+            keep,
+            ignore_budget,
+        } => {
+            let name = name.or(config.default_session.clone()).unwrap_or_default();
+            let savedir = format!("{}/{}", basedir, name);
+            if !Path::new(&savedir).join("context.json").exists() {
+                println!("Are you sure there is a session called {}?", name.blue());
+                return Err(ErrorKind::NotFound.into());
+            }
+            if !ignore_budget {
+                usage::check_budget(config)?;
+            }
+            let flags = Flags {
+                model: config.model.clone(),
+                savedir,
+                network: Network::from_config(config),
+                ..Default::default()
+            };
+            let backend = backend::from_config(config, &flags)?;
+            match api::compact::compact(&flags, backend.as_ref(), keep)? {
+                0 => println!("`{}` is too short to be compacted.", name.yellow()),
+                count => println!(
+                    "{} turns of `{}` were replaced by a summary.",
+                    count,
+                    name.green()
+                ),
+            }
+        }
+        SessionAction::Gc { dry_run } => {
+            utils::collect_images(&basedir, &config.image_store(), dry_run)?
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+
+static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+
+fn syntaxes() -> &'static SyntaxSet {
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme(name: &str) -> &'static Theme {
+    let themes = THEMES.get_or_init(ThemeSet::load_defaults);
+    themes
+        .themes
+        .get(name)
+        .unwrap_or_else(|| &themes.themes[DEFAULT_THEME])
+}
+
+/// Highlights a code block line by line with the given theme.
+///
+/// Returns `None` when the language is not known, so the caller can print the
+/// block without highlighting. Unknown themes fall back to `DEFAULT_THEME`.
+pub fn highlight(lang: &str, code: &str, theme_name: &str) -> Option<Vec<String>> {
+    if lang.is_empty() {
+        return None;
+    }
+    let syntaxes = syntaxes();
+    let syntax = syntaxes
+        .find_syntax_by_token(lang)
+        .or_else(|| syntaxes.find_syntax_by_extension(lang))?;
+    let mut highlighter = HighlightLines::new(syntax, theme(theme_name));
+
+    let mut lines = vec![];
+    for line in LinesWithEndings::from(code) {
+        let ranges = highlighter.highlight_line(line, syntaxes).ok()?;
+        let escaped = as_24_bit_terminal_escaped(&ranges, false);
+        lines.push(format!("{}\x1b[0m", escaped.trim_end_matches(['\n', '\r'])));
+    }
+    Some(lines)
+}
@@ -0,0 +1,553 @@
+use colored::*;
+use pulldown_cmark::{Alignment, CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+use std::env;
+use unicode_width::UnicodeWidthStr;
+
+pub mod highlight;
+pub mod normalize;
+
+const DEFAULT_WIDTH: usize = 80;
+const BULLETS: [&str; 3] = ["•", "◦", "▪"];
+
+/// Renders Markdown as text for the terminal, wrapped to its width, with code
+/// blocks highlighted using `theme`.
+pub fn render(markdown: &str, theme: &str) -> String {
+    if env::var("NO_COLOR").is_ok_and(|value| !value.is_empty()) {
+        colored::control::set_override(false);
+    }
+    let width = terminal_size::terminal_size()
+        .map(|(width, _)| width.0 as usize)
+        .unwrap_or(DEFAULT_WIDTH);
+    return render_with_width(markdown, width, theme);
+}
+
+pub fn render_with_width(markdown: &str, width: usize, theme: &str) -> String {
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut renderer = Renderer::new(width.max(20), theme);
+    for event in Parser::new_ext(markdown, options) {
+        renderer.event(event);
+    }
+    return renderer.finish();
+}
+
+fn colors_enabled() -> bool {
+    colored::control::SHOULD_COLORIZE.should_colorize()
+}
+
+struct Table {
+    alignments: Vec<Alignment>,
+    rows: Vec<Vec<(String, usize)>>,
+    header_rows: usize,
+}
+
+struct Renderer {
+    width: usize,
+    theme: String,
+    out: String,
+    line_started: bool,
+    column: usize,
+    space: bool,
+    blank: bool,
+
+    quote_depth: usize,
+    indent: usize,
+    item_widths: Vec<usize>,
+    lists: Vec<Option<u64>>,
+    marker: Option<String>,
+
+    strong: usize,
+    emphasis: usize,
+    strikethrough: usize,
+    inline_code: bool,
+    heading: Option<HeadingLevel>,
+    link: Option<(String, String)>,
+
+    code: Option<(String, String)>,
+    table: Option<Table>,
+}
+
+impl Renderer {
+    fn new(width: usize, theme: &str) -> Self {
+        Self {
+            width,
+            theme: theme.to_string(),
+            out: String::new(),
+            line_started: false,
+            column: 0,
+            space: false,
+            blank: true,
+            quote_depth: 0,
+            indent: 0,
+            item_widths: vec![],
+            lists: vec![],
+            marker: None,
+            strong: 0,
+            emphasis: 0,
+            strikethrough: 0,
+            inline_code: false,
+            heading: None,
+            link: None,
+            code: None,
+            table: None,
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) => {
+                if let Some((_, code)) = &mut self.code {
+                    code.push_str(&text);
+                } else {
+                    self.push_text(&text);
+                }
+            }
+            Event::Code(code) | Event::InlineMath(code) | Event::DisplayMath(code) => {
+                self.inline_code = true;
+                if colors_enabled() {
+                    self.push_word(&code);
+                } else {
+                    self.push_word(&format!("`{}`", code));
+                }
+                self.inline_code = false;
+            }
+            Event::Html(html) | Event::InlineHtml(html) => self.push_text(&html),
+            Event::FootnoteReference(name) => self.push_word(&format!("[^{}]", name)),
+            Event::SoftBreak => self.space = true,
+            Event::HardBreak => self.end_line(),
+            Event::Rule => {
+                self.start_block();
+                let rule = "─".repeat(self.width.saturating_sub(self.prefix_width()));
+                self.push_raw(&rule.dimmed().to_string(), rule.width());
+                self.end_line();
+            }
+            Event::TaskListMarker(checked) => {
+                self.push_word(if checked { "[x]" } else { "[ ]" });
+            }
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph | Tag::HtmlBlock => self.start_block(),
+            Tag::Heading { level, .. } => {
+                self.start_block();
+                self.heading = Some(level);
+                if !colors_enabled() {
+                    self.push_word(&"#".repeat(level as usize));
+                    self.space = true;
+                }
+            }
+            Tag::BlockQuote(_) => {
+                self.start_block();
+                self.quote_depth += 1;
+            }
+            Tag::CodeBlock(kind) => {
+                self.start_block();
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().unwrap_or("").to_string()
+                    }
+                    CodeBlockKind::Indented => String::new(),
+                };
+                self.code = Some((lang, String::new()));
+            }
+            Tag::List(start) => {
+                if self.lists.is_empty() {
+                    self.start_block();
+                } else {
+                    self.end_line();
+                }
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                self.end_line();
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => {
+                        let depth = self.lists.len().saturating_sub(1) % BULLETS.len();
+                        format!("{} ", BULLETS[depth])
+                    }
+                };
+                self.item_widths.push(marker.width());
+                self.indent += marker.width();
+                self.marker = Some(marker);
+            }
+            Tag::Table(alignments) => {
+                self.start_block();
+                self.table = Some(Table {
+                    alignments,
+                    rows: vec![],
+                    header_rows: 0,
+                });
+            }
+            Tag::TableHead | Tag::TableRow => {
+                if let Some(table) = &mut self.table {
+                    table.rows.push(vec![]);
+                }
+            }
+            Tag::TableCell => {
+                if let Some(row) = self.table.as_mut().and_then(|table| table.rows.last_mut()) {
+                    row.push((String::new(), 0));
+                }
+                self.space = false;
+            }
+            Tag::Emphasis => self.emphasis += 1,
+            Tag::Strong => self.strong += 1,
+            Tag::Strikethrough => self.strikethrough += 1,
+            Tag::Link { dest_url, .. } => self.link = Some((dest_url.to_string(), String::new())),
+            Tag::Image { dest_url, .. } => {
+                self.push_word("[image]");
+                self.space = true;
+                self.link = Some((dest_url.to_string(), String::new()));
+            }
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph | TagEnd::HtmlBlock => self.end_line(),
+            TagEnd::Heading(_) => {
+                self.end_line();
+                self.heading = None;
+            }
+            TagEnd::BlockQuote(_) => {
+                self.end_line();
+                self.quote_depth -= 1;
+            }
+            TagEnd::CodeBlock => {
+                if let Some((lang, code)) = self.code.take() {
+                    self.code_block(&lang, &code);
+                }
+            }
+            TagEnd::List(_) => {
+                self.end_line();
+                self.lists.pop();
+            }
+            TagEnd::Item => {
+                self.end_line();
+                self.indent -= self.item_widths.pop().unwrap_or(0);
+                self.marker = None;
+            }
+            TagEnd::TableHead => {
+                if let Some(table) = &mut self.table {
+                    table.header_rows = table.rows.len();
+                }
+            }
+            TagEnd::Table => {
+                if let Some(table) = self.table.take() {
+                    self.render_table(table);
+                }
+            }
+            TagEnd::Emphasis => self.emphasis -= 1,
+            TagEnd::Strong => self.strong -= 1,
+            TagEnd::Strikethrough => self.strikethrough -= 1,
+            TagEnd::Link | TagEnd::Image => {
+                if let Some((url, text)) = self.link.take() {
+                    if !url.is_empty() && url != text {
+                        self.space = true;
+                        self.push_word(&format!("({})", url).dimmed().to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn style(&self, word: &str) -> String {
+        let mut styled = word.normal();
+        if self.inline_code {
+            styled = styled.yellow();
+        }
+        match self.heading {
+            Some(HeadingLevel::H1) => styled = styled.bold().underline().bright_magenta(),
+            Some(HeadingLevel::H2) => styled = styled.bold().magenta(),
+            Some(_) => styled = styled.bold(),
+            None => {}
+        }
+        if self.strong > 0 {
+            styled = styled.bold();
+        }
+        if self.emphasis > 0 {
+            styled = styled.italic();
+        }
+        if self.strikethrough > 0 {
+            styled = styled.strikethrough();
+        }
+        if self.link.is_some() {
+            styled = styled.blue().underline();
+        }
+        styled.to_string()
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if text.starts_with(char::is_whitespace) {
+            self.space = true;
+        }
+        let mut words = text.split_whitespace().peekable();
+        while let Some(word) = words.next() {
+            self.push_word(word);
+            self.space = words.peek().is_some();
+        }
+        if text.ends_with(char::is_whitespace) {
+            self.space = true;
+        }
+    }
+
+    fn push_word(&mut self, word: &str) {
+        if let Some((_, text)) = &mut self.link {
+            if self.space && !text.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(word);
+        }
+        let styled = self.style(word);
+        let width = strip_width(word);
+
+        if let Some(cell) = self
+            .table
+            .as_mut()
+            .and_then(|table| table.rows.last_mut())
+            .and_then(|row| row.last_mut())
+        {
+            if self.space && cell.1 > 0 {
+                cell.0.push(' ');
+                cell.1 += 1;
+            }
+            cell.0.push_str(&styled);
+            cell.1 += width;
+            self.space = false;
+            return;
+        }
+
+        let mut separator = usize::from(self.space && self.line_started);
+        // a word glued to the previous one, like punctuation, stays with it
+        if separator == 1 && self.column + separator + width > self.width {
+            self.end_line();
+            separator = 0;
+        }
+        if !self.line_started {
+            self.start_line();
+        }
+        if separator == 1 {
+            self.out.push(' ');
+        }
+        self.out.push_str(&styled);
+        self.column += separator + width;
+        self.space = false;
+    }
+
+    fn push_raw(&mut self, text: &str, width: usize) {
+        if !self.line_started {
+            self.start_line();
+        }
+        self.out.push_str(text);
+        self.column += width;
+    }
+
+    fn quote_prefix(&self) -> String {
+        if self.quote_depth == 0 {
+            return String::new();
+        }
+        "│ ".repeat(self.quote_depth).dimmed().to_string()
+    }
+
+    fn prefix_width(&self) -> usize {
+        self.quote_depth * 2 + self.indent
+    }
+
+    fn start_line(&mut self) {
+        self.out.push_str(&self.quote_prefix());
+        match self.marker.take() {
+            Some(marker) => {
+                let padding = self.indent.saturating_sub(marker.width());
+                self.out.push_str(&" ".repeat(padding));
+                self.out.push_str(&marker.cyan().to_string());
+            }
+            None => self.out.push_str(&" ".repeat(self.indent)),
+        }
+        self.column = self.prefix_width();
+        self.line_started = true;
+        self.blank = false;
+    }
+
+    fn end_line(&mut self) {
+        if self.line_started {
+            self.out.push('\n');
+            self.line_started = false;
+        }
+        self.column = 0;
+        self.space = false;
+    }
+
+    /// Ends the current line and separates the next block with an empty line,
+    /// unless it is the first block of a list item.
+    fn start_block(&mut self) {
+        self.end_line();
+        if self.blank || self.marker.is_some() {
+            return;
+        }
+        self.out.push_str(self.quote_prefix().trim_end());
+        self.out.push('\n');
+        self.blank = true;
+    }
+
+    fn code_block(&mut self, lang: &str, code: &str) {
+        let code = code.trim_end_matches('\n').replace('\t', "    ");
+        let highlighted = if colors_enabled() {
+            highlight::highlight(lang, &code, &self.theme)
+        } else {
+            None
+        };
+        let lines = highlighted
+            .unwrap_or_else(|| code.lines().map(|line| line.yellow().to_string()).collect());
+        for (line, plain) in lines.iter().zip(code.lines()) {
+            self.push_raw("    ", 4);
+            self.push_raw(line, plain.width());
+            self.end_line();
+        }
+    }
+
+    fn render_table(&mut self, table: Table) {
+        let columns = table.rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        let mut widths = vec![0; columns];
+        for row in &table.rows {
+            for (index, (_, width)) in row.iter().enumerate() {
+                widths[index] = widths[index].max(*width);
+            }
+        }
+
+        for (index, row) in table.rows.iter().enumerate() {
+            let mut line = String::new();
+            for (column, width) in widths.iter().enumerate() {
+                let (text, text_width) = row.get(column).cloned().unwrap_or_default();
+                let padding = width - text_width;
+                let (left, right) = match table.alignments.get(column) {
+                    Some(Alignment::Right) => (padding, 0),
+                    Some(Alignment::Center) => (padding / 2, padding - padding / 2),
+                    _ => (0, padding),
+                };
+                if column > 0 {
+                    line.push_str(&" │ ".dimmed().to_string());
+                }
+                let cell = if index < table.header_rows {
+                    text.bold().to_string()
+                } else {
+                    text
+                };
+                line.push_str(&" ".repeat(left));
+                line.push_str(&cell);
+                line.push_str(&" ".repeat(right));
+            }
+            let line_width = widths.iter().sum::<usize>() + 3 * columns.saturating_sub(1);
+            self.push_raw(line.trim_end(), line_width);
+            self.end_line();
+
+            if index + 1 == table.header_rows {
+                let separator = widths
+                    .iter()
+                    .map(|width| "─".repeat(*width))
+                    .collect::<Vec<String>>()
+                    .join("─┼─");
+                self.push_raw(&separator.dimmed().to_string(), line_width);
+                self.end_line();
+            }
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.end_line();
+        let mut out = self.out.trim_end().to_string();
+        out.push('\n');
+        out
+    }
+}
+
+/// Width of a word as shown on the terminal, without its escape sequences.
+fn strip_width(text: &str) -> usize {
+    let mut plain = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain.width()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render_with_width;
+
+    fn render(markdown: &str, width: usize) -> String {
+        colored::control::set_override(false);
+        render_with_width(markdown, width, super::highlight::DEFAULT_THEME)
+    }
+
+    #[test]
+    fn separates_headings_and_paragraphs() {
+        assert_eq!(
+            render("# Title\nSome text.\n## Part\nMore.", 40),
+            "# Title\n\nSome text.\n\n## Part\n\nMore.\n"
+        );
+    }
+
+    #[test]
+    fn wraps_paragraphs_to_the_width() {
+        assert_eq!(
+            render("one two three four five six seven", 20),
+            "one two three four\nfive six seven\n"
+        );
+        assert_eq!(
+            render("aaaaaaaaaaaaaaa `bb`, cc", 20),
+            "aaaaaaaaaaaaaaa `bb`,\ncc\n"
+        );
+    }
+
+    #[test]
+    fn indents_nested_lists() {
+        assert_eq!(
+            render("- a\n  - b\n    - c\n- d\n\n1. one\n2. two", 40),
+            "• a\n  ◦ b\n    ▪ c\n• d\n\n1. one\n2. two\n"
+        );
+    }
+
+    #[test]
+    fn aligns_tables() {
+        assert_eq!(
+            render("| a | bb |\n|---|---:|\n| 1 | 2 |\n| 333 | 4 |", 40),
+            "a   │ bb\n────┼───\n1   │  2\n333 │  4\n"
+        );
+    }
+
+    #[test]
+    fn shows_link_targets_after_their_text() {
+        assert_eq!(
+            render(
+                "see [the docs](http://x.y), <http://a.b> and ![alt](i.png)",
+                80
+            ),
+            "see the docs (http://x.y), http://a.b and [image] alt (i.png)\n"
+        );
+    }
+
+    #[test]
+    fn prefixes_quotes() {
+        assert_eq!(
+            render("> quoted\n> > nested\n\nafter", 40),
+            "│ quoted\n│\n│ │ nested\n\nafter\n"
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+use std::ops::Range;
+
+/// Tidies the Markdown of an answer before it is saved.
+///
+/// Only the prose is touched: code blocks, fenced or indented and at any
+/// depth of lists or quotes, inline code spans and HTML are copied as they
+/// are. Line endings are made `\n`, bold text the model wrote with spaces
+/// inside its markers (`**Note: **`) is fixed so it renders, and a code block
+/// left open by a cut off answer is closed.
+pub fn normalize(markdown: &str) -> String {
+    let markdown = markdown.replace("\r\n", "\n");
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut code: Vec<Range<usize>> = vec![];
+    let mut unclosed = None;
+    for (event, range) in Parser::new_ext(&markdown, options).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                if let CodeBlockKind::Fenced(_) = kind {
+                    unclosed = open_fence(&markdown, &range);
+                }
+                code.push(range);
+            }
+            Event::Code(_) | Event::Html(_) | Event::InlineHtml(_) => code.push(range),
+            _ => {}
+        }
+    }
+
+    let mut out = String::with_capacity(markdown.len());
+    let mut last = 0;
+    for range in code {
+        // ranges nest, e.g. the html of a code block
+        if range.start < last {
+            continue;
+        }
+        out.push_str(&normalize_prose(&markdown[last..range.start]));
+        out.push_str(&markdown[range.clone()]);
+        last = range.end;
+    }
+    out.push_str(&normalize_prose(&markdown[last..]));
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    if let Some(fence) = unclosed {
+        out.push_str(&fence);
+        out.push('\n');
+    }
+    return out;
+}
+
+/// Returns the fence closing a fenced code block, indented and quoted as its
+/// opening one, when the block runs unclosed to the end of the answer.
+fn open_fence(markdown: &str, range: &Range<usize>) -> Option<String> {
+    if range.end < markdown.trim_end().len() {
+        return None;
+    }
+    let block = &markdown[range.clone()];
+    let marker = block.chars().find(|c| *c == '`' || *c == '~')?;
+    let opening = block.trim_start_matches(' ');
+    let length = opening.chars().take_while(|c| *c == marker).count();
+    let lines: Vec<&str> = block.trim_end().lines().collect();
+    let closed = lines.len() > 1 && {
+        let closing = lines[lines.len() - 1]
+            .trim_start_matches([' ', '>'])
+            .trim_end();
+        closing.len() >= length && closing.chars().all(|c| c == marker)
+    };
+    if closed {
+        return None;
+    }
+    let line_start = markdown[..range.start].rfind('\n').map_or(0, |i| i + 1);
+    // quotes are kept, list markers become their width in spaces
+    let indent: String = markdown[line_start..range.start]
+        .chars()
+        .map(|c| if c == '>' { c } else { ' ' })
+        .collect();
+    return Some(format!("{}{}", indent, marker.to_string().repeat(length)));
+}
+
+/// Normalizes text outside of code, line by line.
+fn normalize_prose(text: &str) -> String {
+    text.split('\n')
+        .map(fix_emphasis)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Moves the spaces found just inside paired `**` and `__` markers outside of
+/// them. A text with an opening marker left unpaired is left as it is, since
+/// there is no telling which of its markers belong together.
+fn fix_emphasis(text: &str) -> String {
+    let mut text = text.to_string();
+    for marker in ["**", "__"] {
+        let pairs = match pairs(&text, marker) {
+            Some(pairs) if !pairs.is_empty() => pairs,
+            _ => continue,
+        };
+        let mut out = String::with_capacity(text.len());
+        let mut last = 0;
+        for (open, close) in pairs {
+            let inner = &text[open + 2..close];
+            let leading = &inner[..inner.len() - inner.trim_start().len()];
+            let trailing = &inner[inner.trim_end().len()..];
+            out.push_str(&text[last..open]);
+            out.push_str(leading);
+            out.push_str(marker);
+            out.push_str(inner.trim());
+            out.push_str(marker);
+            out.push_str(trailing);
+            last = close + 2;
+        }
+        out.push_str(&text[last..]);
+        text = out;
+    }
+    return text;
+}
+
+/// Byte offsets of the opening and closing markers of the bold text of a
+/// line, `None` when an opening marker is left unpaired.
+///
+/// Only runs of exactly two unescaped markers count, as longer ones mix bold
+/// with italics. A run with spaces on both sides is no marker, and neither is
+/// a run opening right after a word or a bracket, as in `x**2` or
+/// `f(**kwargs)`.
+fn pairs(text: &str, marker: &str) -> Option<Vec<(usize, usize)>> {
+    let delimiter = marker.as_bytes()[0];
+    let bytes = text.as_bytes();
+    let mut pairs = vec![];
+    let mut open = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            byte if byte == delimiter => {
+                let run = bytes[i..].iter().take_while(|b| **b == delimiter).count();
+                let before = text[..i].chars().next_back();
+                let after = text[i + run..].chars().next();
+                let spaced = |c: Option<char>| c.is_some_and(char::is_whitespace);
+                if run == 2 && !(spaced(before) && spaced(after)) {
+                    match open {
+                        Some(start) => {
+                            if text[start + 2..i].trim().is_empty() {
+                                return None;
+                            }
+                            pairs.push((start, i));
+                            open = None;
+                        }
+                        None if opens(before, after) => open = Some(i),
+                        None => {}
+                    }
+                }
+                i += run;
+            }
+            _ => i += 1,
+        }
+    }
+    if open.is_some() {
+        return None;
+    }
+    Some(pairs)
+}
+
+fn opens(before: Option<char>, after: Option<char>) -> bool {
+    let glued = before.is_some_and(|c| c.is_ascii_alphanumeric() || "([{=,_".contains(c));
+    after.is_some() && !glued
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+
+    #[test]
+    fn leaves_fenced_blocks_alone() {
+        let backticks = "```rust\nlet s = \"**a: **\";\n```\n";
+        assert_eq!(normalize(backticks), backticks);
+        let tildes = "~~~\n** b **\n```\nstill code\n~~~\n";
+        assert_eq!(normalize(tildes), tildes);
+    }
+
+    #[test]
+    fn leaves_fences_nested_in_lists_alone() {
+        let text = "1. Call it:\n\n    ```python\n    f(**args, **kwargs)\n    ```\n";
+        assert_eq!(normalize(text), text);
+        let quoted = "> ```\n> ** a **\n> ```\n";
+        assert_eq!(normalize(quoted), quoted);
+    }
+
+    #[test]
+    fn leaves_indented_code_blocks_alone() {
+        let text = "Run:\n\n    x = {**a, **b}\n    y = 2 ** 3\n";
+        assert_eq!(normalize(text), text);
+    }
+
+    #[test]
+    fn leaves_code_in_prose_alone() {
+        for text in ["call f(**args, **kwargs) now\n", "x**2 + y**2 **is** z\n"] {
+            assert_eq!(normalize(text), text);
+        }
+    }
+
+    #[test]
+    fn leaves_inline_code_alone() {
+        assert_eq!(
+            normalize("use `**x **` or ``a ` **b **``"),
+            "use `**x **` or ``a ` **b **``\n"
+        );
+    }
+
+    #[test]
+    fn moves_spaces_out_of_bold_markers() {
+        assert_eq!(normalize("**Note: **"), "**Note:** \n");
+        assert_eq!(normalize("**Note: **read this"), "**Note:** read this\n");
+        assert_eq!(normalize("** Bold** text"), " **Bold** text\n");
+    }
+
+    #[test]
+    fn leaves_urls_and_times_alone() {
+        let text = "see https://example.com/a:b at 10:30\n";
+        assert_eq!(normalize(text), text);
+    }
+
+    #[test]
+    fn leaves_unpaired_markers_alone() {
+        assert_eq!(normalize("2 ** 3 is **eight "), "2 ** 3 is **eight \n");
+        assert_eq!(normalize("a ** b"), "a ** b\n");
+    }
+
+    #[test]
+    fn closes_unterminated_fences() {
+        assert_eq!(normalize("```py\nprint(1)"), "```py\nprint(1)\n```\n");
+        assert_eq!(normalize("~~~~\nx\n~~~"), "~~~~\nx\n~~~\n~~~~\n");
+        assert_eq!(
+            normalize("- step\n\n  ```sh\n  ls"),
+            "- step\n\n  ```sh\n  ls\n  ```\n"
+        );
+    }
+
+    #[test]
+    fn handles_multibyte_text_next_to_markers() {
+        assert_eq!(normalize("**héllo: **wörld"), "**héllo:** wörld\n");
+        assert_eq!(normalize("日本**語 **です"), "日本**語** です\n");
+        assert_eq!(normalize("\\é**ü **"), "\\é**ü** \n");
+    }
+}
@@ -1,2 +0,0 @@
-#include <stdlib.h>
-void run(char *cmd) { system(cmd); }
@@ -0,0 +1,212 @@
+use chrono::{DateTime, Datelike, Local, NaiveDate};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{self, json};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{ErrorKind, Result, Write};
+use std::path::Path;
+
+use crate::backend::{FinishReason, Reply, Usage};
+use crate::config::configfile::Config;
+use crate::utils;
+
+/// Tokens used by one request.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Record {
+    pub time: DateTime<Local>,
+    pub model: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<FinishReason>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub safety: Vec<String>,
+}
+
+/// The requests made for a session, kept in `usage.json`.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SessionUsage {
+    pub total: Usage,
+    pub requests: Vec<Record>,
+}
+
+pub fn load(savedir: &str) -> Result<SessionUsage> {
+    let path = format!("{}/usage.json", savedir);
+    if !Path::new(&path).exists() {
+        return Ok(SessionUsage::default());
+    }
+    let text = fs::read_to_string(&path)?;
+    let usage: SessionUsage = serde_json::from_str(&text)?;
+    return Ok(usage);
+}
+
+pub fn save(savedir: &str, usage: &SessionUsage) -> Result<()> {
+    let mut file = utils::overwrite(&format!("{}/usage.json", savedir))?;
+    file.write_all(&json!(usage).to_string().into_bytes())?;
+    return Ok(());
+}
+
+/// Adds the tokens used by a request to the session, along with how its
+/// answer ended.
+pub fn record(savedir: &str, model: &str, reply: &Reply) -> Result<()> {
+    let usage = reply.usage.unwrap_or_default();
+    let mut session = load(savedir)?;
+    session.total.add(&usage);
+    session.requests.push(Record {
+        time: Local::now(),
+        model: model.into(),
+        usage,
+        finish_reason: reply.finish_reason.clone(),
+        safety: reply.safety.clone(),
+    });
+    return save(savedir, &session);
+}
+
+/// Estimated cost of the tokens used with a model, when its prices are known.
+pub fn cost(config: &Config, model: &str, usage: &Usage) -> Option<f64> {
+    let price = config.prices.as_ref()?.get(model)?;
+    Some(
+        (usage.prompt_tokens as f64 * price.input + usage.candidate_tokens as f64 * price.output)
+            / 1_000_000.0,
+    )
+}
+
+/// Estimated cost of the requests of a session, when the prices of all the
+/// models it used are known.
+pub fn session_cost(config: &Config, session: &SessionUsage) -> Option<f64> {
+    session
+        .requests
+        .iter()
+        .map(|request| cost(config, &request.model, &request.usage))
+        .sum()
+}
+
+/// Refuses to send a prompt once a limit of the budget set in the config is
+/// reached. Requests to models without a price do not count towards the cost
+/// limits.
+pub fn check_budget(config: &Config) -> Result<()> {
+    let budget = match &config.budget {
+        Some(budget) => budget,
+        None => return Ok(()),
+    };
+    let basedir = config.base_path();
+    let today = Local::now().date_naive();
+    let (mut daily_tokens, mut monthly_tokens) = (0, 0);
+    let (mut daily_cost, mut monthly_cost) = (0.0, 0.0);
+    for savedir in utils::session_dirs(&basedir)? {
+        for request in load(&savedir)?.requests {
+            let date = request.time.date_naive();
+            if date.year() != today.year() || date.month() != today.month() {
+                continue;
+            }
+            let tokens = request.usage.total_tokens;
+            let cost = cost(config, &request.model, &request.usage).unwrap_or(0.0);
+            monthly_tokens += tokens;
+            monthly_cost += cost;
+            if date == today {
+                daily_tokens += tokens;
+                daily_cost += cost;
+            }
+        }
+    }
+
+    let limits = [
+        (
+            "daily token",
+            budget.daily_tokens.map(|limit| limit as f64),
+            daily_tokens as f64,
+        ),
+        (
+            "monthly token",
+            budget.monthly_tokens.map(|limit| limit as f64),
+            monthly_tokens as f64,
+        ),
+        ("daily cost", budget.daily_cost, daily_cost),
+        ("monthly cost", budget.monthly_cost, monthly_cost),
+    ];
+    for (name, limit, used) in limits {
+        if let Some(limit) = limit {
+            if used >= limit {
+                eprintln!(
+                    "{} : the {} budget of {} is spent ({} used), pass {} to send the prompt anyway",
+                    "Error".red(),
+                    name,
+                    limit,
+                    (used * 10_000.0).round() / 10_000.0,
+                    "--ignore-budget".green()
+                );
+                return Err(ErrorKind::Other.into());
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Prints the tokens used by day and model, for every session or only for
+/// the given one.
+pub fn report(config: &Config, session: Option<&str>) -> Result<()> {
+    let basedir = config.base_path();
+    let savedirs = match session {
+        Some(session) => vec![format!("{}/{}", basedir, session)],
+        None => utils::session_dirs(&basedir)?,
+    };
+
+    let mut rows: BTreeMap<(NaiveDate, String), (u64, Usage)> = BTreeMap::new();
+    for savedir in &savedirs {
+        for request in load(savedir)?.requests {
+            let row = rows
+                .entry((request.time.date_naive(), request.model))
+                .or_default();
+            row.0 += 1;
+            row.1.add(&request.usage);
+        }
+    }
+    if rows.is_empty() {
+        println!("No usage was recorded yet.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<12}{:<28}{:>10}{:>12}{:>12}{:>12}{:>12}",
+        "DATE", "MODEL", "REQUESTS", "PROMPT", "OUTPUT", "TOTAL", "COST"
+    );
+    let mut requests = 0;
+    let mut total = Usage::default();
+    let mut total_cost = Some(0.0);
+    for ((date, model), (count, usage)) in &rows {
+        let cost = cost(config, model, usage);
+        println!(
+            "{:<12}{:<28}{:>10}{:>12}{:>12}{:>12}{:>12}",
+            date.to_string(),
+            model,
+            count,
+            usage.prompt_tokens,
+            usage.candidate_tokens,
+            usage.total_tokens,
+            format_cost(cost)
+        );
+        requests += count;
+        total.add(usage);
+        total_cost = total_cost.zip(cost).map(|(total, cost)| total + cost);
+    }
+    println!(
+        "{}",
+        format!(
+            "{:<40}{:>10}{:>12}{:>12}{:>12}{:>12}",
+            "TOTAL",
+            requests,
+            total.prompt_tokens,
+            total.candidate_tokens,
+            total.total_tokens,
+            format_cost(total_cost)
+        )
+        .bold()
+    );
+    return Ok(());
+}
+
+fn format_cost(cost: Option<f64>) -> String {
+    cost.map(|cost| format!("{:.4}", cost))
+        .unwrap_or("-".into())
+}
@@ -1,67 +1,181 @@
+use base64::prelude::*;
 use colored::*;
-use rand::{self, Rng};
-use regex::Regex;
 use sha256;
+use std::collections::HashSet;
 use std::fs;
 use std::io::{self, ErrorKind, Result};
-use std::iter;
 use std::path::{self, Path};
-use std::process::Command;
 
 use crate::Flags;
 
-pub fn open(file_path: &String) -> Result<std::fs::File> {
+pub fn open(file_path: &str) -> Result<std::fs::File> {
     fs::OpenOptions::new()
-        .write(true)
         .read(true)
         .create(true)
         .append(true)
         .open(file_path)
 }
 
-pub fn overwrite(file_path: &String) -> Result<fs::File> {
+pub fn overwrite(file_path: &str) -> Result<fs::File> {
     let res_path = path::Path::new(file_path);
     if res_path.exists() {
         fs::remove_file(res_path)?;
     }
     fs::OpenOptions::new()
         .create(true)
-        .append(false)
+        .truncate(true)
         .write(true)
         .read(true)
         .open(file_path)
 }
 
-pub fn process_newlines(input: &str) -> String {
-    let placeholder = "__ESCAPED_N__";
-    let re_escaped_n = Regex::new(r"\\\\n").unwrap();
-    let intermediate = re_escaped_n.replace_all(&input, placeholder);
+/// Image formats accepted by the model: media type, extension of the stored
+/// copy and a readable name.
+const IMAGE_TYPES: &[(&str, &str, &str)] = &[
+    ("image/png", "png", "PNG"),
+    ("image/jpeg", "jpg", "JPEG"),
+    ("image/webp", "webp", "WEBP"),
+    ("image/heic", "heic", "HEIC"),
+    ("image/heif", "heif", "HEIF"),
+];
 
-    let re_newline = Regex::new(r"\\n").unwrap();
-    let result = re_newline.replace_all(&intermediate, "\n");
+/// Returns the media type of an image from its first bytes.
+pub fn image_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        return Some("image/jpeg");
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+        return match &bytes[8..12] {
+            b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" => Some("image/heic"),
+            b"mif1" | b"msf1" | b"heif" => Some("image/heif"),
+            _ => None,
+        };
+    }
+    return None;
+}
 
-    let result = result.replace(placeholder, "\\n");
-    return result;
+/// Returns the extension used to store an image of the given media type.
+pub fn image_extension(mime_type: &str) -> &'static str {
+    IMAGE_TYPES
+        .iter()
+        .find(|(known, _, _)| *known == mime_type)
+        .map(|(_, ext, _)| *ext)
+        .unwrap_or("img")
 }
 
-pub fn read_image(path: &str) -> Result<(String, String)> {
-    let output = Command::new("base64")
-        .arg("-w0")
-        .arg(path.trim())
-        .output()
-        .unwrap();
+/// Checks that a file is a supported image and returns the SHA-256 of its
+/// content, which names its copy, and its media type, detected from the
+/// content of the file.
+pub fn read_image(path: &str) -> Result<(String, &'static str)> {
+    let bytes = match fs::read(path.trim()) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!(
+                "{} : could not read {} : {}",
+                "Error".red(),
+                path.yellow(),
+                err
+            );
+            return Err(err);
+        }
+    };
+    let mime_type = match image_type(&bytes) {
+        Some(mime_type) => mime_type,
+        None => {
+            let supported: Vec<&str> = IMAGE_TYPES.iter().map(|(_, _, name)| *name).collect();
+            eprintln!(
+                "{} : {} is not a supported image, the supported formats are {}",
+                "Error".red(),
+                path.yellow(),
+                supported.join(", ")
+            );
+            return Err(ErrorKind::InvalidData.into());
+        }
+    };
+    let hash = sha256::digest(&bytes[..]);
+    return Ok((hash, mime_type));
+}
 
-    if output.status.success() {
-        let data = String::from_utf8(output.stdout.clone()).unwrap();
-        let hash = generate_random_hash();
-        return Ok((hash, data));
-    } else {
-        let data = String::from_utf8(output.stderr.clone()).unwrap();
-        println!("{} :\n{}", "Base64 Error".red(), data);
-        return Err(ErrorKind::Other.into());
+/// Returns the base64 encoded content of a stored image.
+pub fn encode_image(path: &str) -> Result<String> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(BASE64_STANDARD.encode(bytes)),
+        Err(err) => {
+            eprintln!(
+                "{} : the image {} of the session could not be read : {}",
+                "Error".red(),
+                path.yellow(),
+                err
+            );
+            Err(err)
+        }
     }
 }
 
+/// Reads a text file to attach to a prompt, refusing files over `limit` bytes
+/// and files that look binary.
+pub fn read_text_file(path: &str, limit: u64) -> Result<String> {
+    let size = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(err) => {
+            eprintln!(
+                "{} : could not read {} : {}",
+                "Error".red(),
+                path.yellow(),
+                err
+            );
+            return Err(err);
+        }
+    };
+    if size > limit {
+        eprintln!(
+            "{} : {} is {} bytes, the limit for attached files is {} bytes",
+            "Error".red(),
+            path.yellow(),
+            size,
+            limit
+        );
+        return Err(ErrorKind::InvalidInput.into());
+    }
+    let data = fs::read(path)?;
+    if data.iter().take(8192).any(|byte| *byte == 0) {
+        eprintln!(
+            "{} : {} looks like a binary file",
+            "Error".red(),
+            path.yellow()
+        );
+        return Err(ErrorKind::InvalidData.into());
+    }
+    match String::from_utf8(data) {
+        Ok(text) => Ok(text),
+        Err(_) => {
+            eprintln!(
+                "{} : {} is not valid UTF-8 text",
+                "Error".red(),
+                path.yellow()
+            );
+            Err(ErrorKind::InvalidData.into())
+        }
+    }
+}
+
+/// Returns the paths referenced as `@path` in a prompt that point to files.
+pub fn file_references(prompt: &str) -> Vec<String> {
+    prompt
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|path| path.trim_end_matches([',', '.', ';', ':', '!', '?', ')', '"', '\'']))
+        .filter(|path| !path.is_empty() && Path::new(path).is_file())
+        .map(|path| path.to_string())
+        .collect()
+}
+
 pub fn get_absolute_path(path: &str) -> Result<String> {
     let path = path::Path::new(path);
     let absolute_path = path.canonicalize()?;
@@ -71,58 +185,141 @@ pub fn get_absolute_path(path: &str) -> Result<String> {
 pub fn make_session(flags: &Flags) -> Result<()> {
     let savedir = &flags.savedir.clone();
     let path = Path::new(&savedir);
-    if !path.exists() {
+    if !path.join("context.json").exists() {
         let _ = fs::create_dir_all(path.to_str().unwrap());
         crate::context::initialize_context(&flags.savedir.clone())?;
     };
     return Ok(());
 }
 
-fn generate_random_hash() -> String {
-    let mut rng = rand::thread_rng();
-    let random_string: String = iter::repeat(())
-        .map(|()| rng.sample(rand::distributions::Alphanumeric))
-        .take(30)
-        .map(char::from)
-        .collect();
+/// Stores an image in the session under the hash of its content, so the same
+/// image is only stored once. With a shared `store` the image is kept there
+/// and hard linked into the session, which deduplicates it across sessions.
+pub fn copy_image(
+    source: &str,
+    savedir: &str,
+    hash: &str,
+    mime_type: &str,
+    store: Option<&str>,
+) -> Result<String> {
+    let name = format!("{}.{}", hash, image_extension(mime_type));
+    let dest = Path::new(savedir).join("images").join(&name);
+    if !dest.exists() {
+        fs::create_dir_all(Path::new(savedir).join("images"))?;
+        match store {
+            Some(store) => {
+                fs::create_dir_all(store)?;
+                let shared = Path::new(store).join(&name);
+                if !shared.exists() {
+                    fs::copy(source.trim(), &shared)?;
+                }
+                if fs::hard_link(&shared, &dest).is_err() {
+                    fs::copy(&shared, &dest)?;
+                }
+            }
+            None => {
+                fs::copy(source.trim(), &dest)?;
+            }
+        }
+    }
+    return Ok(format!("./images/{}", name));
+}
 
-    let hash = sha256::digest(random_string);
+/// Removes the images of the sessions that their conversation does not refer
+/// to anymore, along with the images of the shared `store` no session uses.
+pub fn collect_images(basedir: &str, store: &str, dry_run: bool) -> Result<()> {
+    let mut referenced = HashSet::new();
+    let mut garbage = vec![];
+    for savedir in session_dirs(basedir)? {
+        let savedir = Path::new(&savedir);
+        let used = used_images(savedir)?;
+        if let Ok(entries) = fs::read_dir(savedir.join("images")) {
+            for entry in entries {
+                let path = entry?.path();
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                if !used.contains(&name) {
+                    garbage.push(path);
+                }
+            }
+        }
+        referenced.extend(used);
+    }
+    if let Ok(entries) = fs::read_dir(store) {
+        for entry in entries {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if !referenced.contains(&name) {
+                garbage.push(path);
+            }
+        }
+    }
 
-    return hash;
+    let mut freed = 0;
+    for path in &garbage {
+        freed += fs::metadata(path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        if dry_run {
+            println!("{}", path.to_string_lossy());
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    println!(
+        "{} {} unreferenced images ({} bytes).",
+        if dry_run { "Found" } else { "Removed" },
+        garbage.len().to_string().yellow(),
+        freed
+    );
+    return Ok(());
 }
 
-pub fn copy_image(source: &String, savedir: &String, hash: &String) -> Result<String> {
-    let savefile = format!(
-        "{}/images/{}.{}",
-        savedir,
-        hash,
-        source.split('.').last().unwrap()
-    );
-    println!("source : {}\ndest : {}", source, savefile);
-    fs::create_dir_all(format!("{}/images", savedir))?;
-    fs::copy(source, &savefile)?;
-    return Ok(format!(
-        "./images/{}.{}",
-        hash,
-        source.split('.').last().unwrap()
-    ));
+/// Names of the images the conversation of a session refers to, including
+/// the history archived under `history/` when it was compacted.
+fn used_images(savedir: &Path) -> Result<HashSet<String>> {
+    let mut contexts = vec![savedir.join("context.json")];
+    if let Ok(entries) = fs::read_dir(savedir.join("history")) {
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                contexts.push(path);
+            }
+        }
+    }
+    let mut used = HashSet::new();
+    for path in contexts.iter().filter(|path| path.exists()) {
+        let context: crate::context::Context = serde_json::from_str(&fs::read_to_string(path)?)?;
+        used.extend(
+            crate::context::image_paths(&context)
+                .iter()
+                .filter_map(|path| Path::new(path).file_name())
+                .map(|name| name.to_string_lossy().to_string()),
+        );
+    }
+    return Ok(used);
 }
 
-pub fn delete_session(path: &String, session: &String) -> Result<()> {
+pub fn delete_session(path: &str, session: &str, confirmed: bool) -> Result<()> {
     println!("path : {}", path);
-    let dir = Path::new(&path);
+    let dir = Path::new(path);
     if dir.exists() {
-        println!(
-            "Do you really want to {} the session {}?[Y/N]",
-            "delete".red(),
-            session.yellow()
-        );
-        let mut ch = String::new();
-        io::stdin().read_line(&mut ch)?;
+        let mut ch = String::from("y");
+        if !confirmed {
+            println!(
+                "Do you really want to {} the session {}?[Y/N]",
+                "delete".red(),
+                session.yellow()
+            );
+            ch.clear();
+            io::stdin().read_line(&mut ch)?;
+        }
         let ch: String = ch.trim().to_string();
         match &*ch {
             "Y" | "y" => {
-                fs::remove_dir_all(&dir.to_str().unwrap())?;
+                fs::remove_dir_all(dir.to_str().unwrap())?;
                 println!("`{}` session was deleted successfully.", session.red());
             }
             _ => {
@@ -132,5 +329,75 @@ pub fn delete_session(path: &String, session: &String) -> Result<()> {
     } else {
         println!("Are you sure there is a session called {}?", session.blue());
     }
-    std::process::exit(0);
+    return Ok(());
+}
+
+pub fn rename_session(basedir: &str, from: &str, to: &str) -> Result<()> {
+    let source = Path::new(basedir).join(from);
+    let dest = Path::new(basedir).join(to);
+    if !source.join("context.json").exists() {
+        println!("Are you sure there is a session called {}?", from.blue());
+        return Err(ErrorKind::NotFound.into());
+    }
+    if dest.exists() {
+        println!("A session called {} already exists.", to.yellow());
+        return Err(ErrorKind::AlreadyExists.into());
+    }
+    fs::rename(source, dest)?;
+    println!(
+        "`{}` session was renamed to `{}`.",
+        from.yellow(),
+        to.green()
+    );
+    return Ok(());
+}
+
+/// Returns the names of the sessions stored in the base directory, nested
+/// sessions named by their path from it, e.g. `team/x`.
+pub fn list_sessions(basedir: &str) -> Result<Vec<String>> {
+    let mut sessions = vec![];
+    find_sessions(Path::new(basedir), "", &mut sessions)?;
+    sessions.sort();
+    return Ok(sessions);
+}
+
+/// Returns the directories of every session, including the base directory
+/// itself, which holds the conversation when no session is chosen.
+pub fn session_dirs(basedir: &str) -> Result<Vec<String>> {
+    let mut dirs = vec![];
+    if is_session(Path::new(basedir)) {
+        dirs.push(basedir.to_string());
+    }
+    for session in list_sessions(basedir)? {
+        dirs.push(format!("{}/{}", basedir, session));
+    }
+    return Ok(dirs);
+}
+
+fn is_session(dir: &Path) -> bool {
+    dir.join("context.json").exists() || dir.join("usage.json").exists()
+}
+
+fn find_sessions(dir: &Path, prefix: &str, sessions: &mut Vec<String>) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // the data of a session and the shared image store are no sessions
+        if name.starts_with('.') || name == "images" || name == "history" {
+            continue;
+        }
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let session = format!("{}{}", prefix, name);
+        if is_session(&entry.path()) {
+            sessions.push(session.clone());
+        }
+        find_sessions(&entry.path(), &format!("{}/", session), sessions)?;
+    }
+    return Ok(());
 }